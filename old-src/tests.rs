@@ -1,7 +0,0 @@
-
-
-
-#[test]
-fn foo() {
-    assert!(true)
-}
@@ -0,0 +1,169 @@
+use bitvec::prelude::*;
+use openssl::error::ErrorStack;
+use std::fmt;
+
+use crate::keyword::{hmac, Key};
+
+/// Random draws made before falling back to a scan of the free blocks
+const PROBES: u64 = 16;
+
+/// Chooses the free block an aspect grows into
+///
+/// `usage_map` has a bit set for every block in use, `key` is the aspect's
+/// allocation key, and `previous` is the index of the block that will point
+/// at the new one: the seed sector for block 0 of the aspect, the end of the
+/// chain otherwise. The chosen block must be free, and `None` may only be
+/// returned if none is. Errors come from deriving the choice from the key.
+pub trait Allocator: fmt::Debug + Send {
+    fn choose(
+        &self,
+        usage_map: &BitSlice,
+        key: &Key,
+        previous: u64,
+        block_id: u64,
+    ) -> Result<Option<u64>, ErrorStack>;
+}
+
+/// Takes the first free block after the previous one, wrapping around the end
+/// of the extent
+///
+/// Aspects end up laid out contiguously, which makes their size and location
+/// plain to anyone comparing snapshots of the disk. Useful for tests and
+/// benchmarks, where a predictable layout helps.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Linear;
+
+impl Allocator for Linear {
+    fn choose(
+        &self,
+        usage_map: &BitSlice,
+        _key: &Key,
+        previous: u64,
+        _block_id: u64,
+    ) -> Result<Option<u64>, ErrorStack> {
+        let n_blocks = usage_map.len() as u64;
+        Ok((0..n_blocks)
+            .map(|offset| (previous + offset) % n_blocks)
+            .find(|&index| !usage_map[index as usize]))
+    }
+}
+
+/// Scatters an aspect's blocks uniformly over the free space
+///
+/// Blocks are drawn from a stream keyed by the aspect's allocation key and
+/// the block's position in the chain, so without the passphrase the choice
+/// can't be told from random. A few draws are tried at random; should those
+/// all land on blocks in use, the free block is picked by rank instead.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Random;
+
+impl Random {
+    fn draw(key: &Key, block_id: u64, label: &[u8], counter: u64) -> Result<u64, ErrorStack> {
+        let output = hmac(
+            key,
+            &[label, &block_id.to_be_bytes(), &counter.to_be_bytes()],
+        )?;
+        Ok(u64::from_be_bytes(output[..8].try_into().unwrap()))
+    }
+}
+
+impl Allocator for Random {
+    fn choose(
+        &self,
+        usage_map: &BitSlice,
+        key: &Key,
+        _previous: u64,
+        block_id: u64,
+    ) -> Result<Option<u64>, ErrorStack> {
+        let n_blocks = usage_map.len() as u64;
+        if n_blocks == 0 {
+            return Ok(None);
+        }
+        for probe in 0..PROBES {
+            let index = Random::draw(key, block_id, b"probe", probe)? % n_blocks;
+            if !usage_map[index as usize] {
+                return Ok(Some(index));
+            }
+        }
+        let n_free = usage_map.count_zeros() as u64;
+        if n_free == 0 {
+            return Ok(None);
+        }
+        let rank = Random::draw(key, block_id, b"rank", 0)? % n_free;
+        Ok(usage_map
+            .iter_zeros()
+            .nth(rank as usize)
+            .map(|index| index as u64))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn linear_wraps() {
+        let mut usage_map = bitvec![0; 8];
+        usage_map[5..].fill(true);
+        assert_eq!(Linear.choose(&usage_map, &[0; 32], 3, 0).unwrap(), Some(3));
+        assert_eq!(Linear.choose(&usage_map, &[0; 32], 6, 0).unwrap(), Some(0));
+        usage_map.fill(true);
+        assert_eq!(Linear.choose(&usage_map, &[0; 32], 6, 0).unwrap(), None);
+    }
+
+    #[test]
+    fn random_is_keyed() {
+        let usage_map = bitvec![0; 1 << 16];
+        let choose = |key: &Key, block_id| {
+            Random
+                .choose(&usage_map, key, 0, block_id)
+                .unwrap()
+                .unwrap()
+        };
+        assert_eq!(choose(&[1; 32], 5), choose(&[1; 32], 5));
+        assert_ne!(choose(&[1; 32], 5), choose(&[2; 32], 5));
+        assert_ne!(choose(&[1; 32], 5), choose(&[1; 32], 6));
+    }
+
+    #[test]
+    fn random_finds_last_free_block() {
+        let mut usage_map = bitvec![1; 4096];
+        usage_map.set(1234, false);
+        for block_id in 0..8 {
+            assert_eq!(
+                Random.choose(&usage_map, &[7; 32], 0, block_id).unwrap(),
+                Some(1234)
+            );
+        }
+        usage_map.set(1234, true);
+        assert_eq!(Random.choose(&usage_map, &[7; 32], 0, 0).unwrap(), None);
+        assert_eq!(
+            Random.choose(BitSlice::empty(), &[7; 32], 0, 0).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn random_scatters() {
+        let mut usage_map = bitvec![0; 1024];
+        let mut previous = 0;
+        let mut adjacent = 0;
+        for block_id in 0..64 {
+            let index = Random
+                .choose(&usage_map, &[3; 32], previous, block_id)
+                .unwrap()
+                .unwrap();
+            assert!(!usage_map[index as usize]);
+            usage_map.set(index as usize, true);
+            if index == previous + 1 {
+                adjacent += 1;
+            }
+            previous = index;
+        }
+        assert!(
+            adjacent < 8,
+            "{} of 64 blocks follow their predecessor",
+            adjacent
+        );
+    }
+}
@@ -0,0 +1,1262 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::sync::Arc;
+
+use crate::block::{self, END_OF_CHAIN};
+use crate::error::Result;
+use crate::extent::{Device, SALT_BLOCK};
+use crate::keyword::BlockKey;
+use crate::seed::SeedKey;
+use crate::{Block, EncryptedBlock, Extent, ExtentHandle, Keyword, RawBlock};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The seed could not be located in the seed block
+    SeedNotFound,
+    /// The decoded pointer is bad
+    BadPointer,
+    /// Could not read a superblock header
+    BadSuperBlock,
+    /// A block of this aspect has been tampered with
+    AuthenticationFailed,
+    /// There are no free blocks left on the extent
+    ExtentFull,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<block::Error> for Error {
+    fn from(e: block::Error) -> Error {
+        match e {
+            // A pointer within the chain led somewhere we don't own
+            block::Error::NotMyBlock => Error::BadSuperBlock,
+            block::Error::AuthenticationFailed => Error::AuthenticationFailed,
+        }
+    }
+}
+
+/// Blocks that fail to decrypt within a chain mean the aspect is damaged
+fn chain_error(e: crate::Error) -> crate::Error {
+    match e {
+        crate::Error::Block(e) => Error::from(e).into(),
+        e => e,
+    }
+}
+
+pub struct Aspect {
+    extent_handle: ExtentHandle,
+    /// Shared with the extent, which needs it to re-encrypt blocks as chaff
+    keyword: Arc<Keyword>,
+    blocks: Vec<u64>,
+    /// The seed sector and slot holding the pointer to the first block
+    seed: Option<(u64, usize)>,
+}
+
+impl Aspect {
+    pub fn new(extent: &ExtentHandle, keyword: Keyword) -> Aspect {
+        Aspect {
+            extent_handle: extent.clone(),
+            keyword: Arc::new(keyword),
+            blocks: Vec::new(),
+            seed: None,
+        }
+    }
+
+    /// Scan forward from the seed index for a seed sector holding a pointer
+    /// to a block that authenticates as the first block of this aspect
+    fn find_seed(&self, extent: &mut Extent<Device>) -> Result<(u64, usize, u64, Block)> {
+        let n_blocks = extent.n_blocks();
+        let seed_key = SeedKey::new(&self.keyword, self.block_size())?;
+        let seed_index = self.seed_index()?;
+        for offset in 0..n_blocks {
+            let sector = (seed_index + offset) % n_blocks;
+            if sector == SALT_BLOCK {
+                continue;
+            }
+            let raw = extent.read_raw(sector)?;
+            for (slot, pointer) in seed_key.read(&raw) {
+                if pointer >= n_blocks || pointer == sector || pointer == SALT_BLOCK {
+                    continue;
+                }
+                match extent
+                    .read_block(pointer)?
+                    .decrypt(&self.block_key(0)?, pointer, 0)
+                {
+                    Ok(block) if block.validate_checksum() => {
+                        return Ok((sector, slot, pointer, block))
+                    }
+                    Ok(_) => return Err(Error::BadSuperBlock.into()),
+                    Err(crate::Error::Block(block::Error::NotMyBlock)) => (),
+                    Err(e) => return Err(chain_error(e)),
+                }
+            }
+        }
+        Err(Error::SeedNotFound.into())
+    }
+
+    /// Read an extent from the disk
+    pub fn read_aspect(extent: &ExtentHandle, keyword: Keyword) -> Result<Aspect> {
+        let mut aspect = Aspect::new(extent, keyword);
+        let n_blocks = extent.n_blocks();
+        let mut extent = extent.lock()?;
+
+        let (sector, slot, index, mut block) = aspect.find_seed(&mut extent)?;
+        // The keyword's Display shows the passphrase, so keep it out of the log
+        log::debug!("Found seed at 0x{:x}:{}", sector, slot);
+        aspect.blocks.push(index);
+
+        // Parse until the chain terminates, refusing to revisit a block
+        let mut visited = HashSet::from([index]);
+        while !block.is_last() {
+            let next = block.next_sector_id();
+            if next >= n_blocks || !visited.insert(next) {
+                return Err(Error::BadPointer.into());
+            }
+            block = extent
+                .read_block(next)?
+                .decrypt(&aspect.block_key(aspect.len())?, next, aspect.len())
+                .map_err(chain_error)?;
+            if !block.validate_checksum() {
+                return Err(Error::BadSuperBlock.into());
+            }
+            aspect.blocks.push(next);
+        }
+
+        extent.claim_seed_slot(sector, slot);
+        for (position, &index) in aspect.blocks.iter().enumerate() {
+            extent.claim_block(index, &aspect.keyword, position as u64);
+        }
+        drop(extent);
+        aspect.seed = Some((sector, slot));
+        Ok(aspect)
+    }
+
+    pub fn read_block(&mut self, block_id: u64) -> Result<Block> {
+        if block_id >= self.len() {
+            return Err(Error::BadPointer.into());
+        }
+        let index = self.transform_block_id(block_id);
+        let block = self
+            .extent_handle
+            .lock()?
+            .read_block(index)?
+            .decrypt(&self.block_key(block_id)?, index, block_id)
+            .map_err(chain_error)?;
+        if !block.validate_checksum() {
+            return Err(Error::BadSuperBlock.into());
+        }
+        Ok(block)
+    }
+
+    pub fn len(&self) -> u64 {
+        self.blocks.len() as u64
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    /// Size of each block of the aspect on disk
+    pub fn block_size(&self) -> usize {
+        self.extent_handle.block_size()
+    }
+
+    /// Bytes of data carried by each block
+    pub fn data_size(&self) -> usize {
+        Block::data_size(self.block_size())
+    }
+
+    /// The extent this aspect lives on
+    pub fn extent(&self) -> &ExtentHandle {
+        &self.extent_handle
+    }
+
+    /// Physical indices of every block in the chain, in order
+    pub fn blocks(&self) -> &[u64] {
+        &self.blocks
+    }
+
+    fn transform_block_id(&self, block_id: u64) -> u64 {
+        self.blocks[block_id as usize]
+    }
+
+    /// Calculate the first possible seed index
+    fn seed_index(&self) -> Result<u64> {
+        Ok(self.keyword.seed_index(self.extent_handle.n_blocks())?)
+    }
+
+    /// The seed sector holding the pointer to this aspect's first block
+    pub fn seed_sector(&self) -> Option<u64> {
+        self.seed.map(|(sector, _)| sector)
+    }
+
+    /// Allocate the first block of the aspect, and store a pointer to it in a
+    /// seed sector at or after the seed index. The other slots of the seed
+    /// sector are left untouched, so aspects may share it
+    pub fn write_seed_block(&mut self) -> Result<()> {
+        let seed_key = SeedKey::new(&self.keyword, self.block_size())?;
+        let seed_index = self.seed_index()?;
+        let mut extent = self.extent_handle.lock()?;
+        // Claiming the slot counts the new aspect as unlocked, so check first
+        extent.check_guard()?;
+        let (sector, slot) = extent
+            .alloc_seed_slot(seed_index, seed_key.candidates())
+            .ok_or(Error::ExtentFull)?;
+        let index = match extent.alloc_block_for(&self.keyword, sector, 0) {
+            Ok(Some(index)) => index,
+            result => {
+                extent.release_seed_slot(sector, slot);
+                return Err(result.err().unwrap_or(Error::ExtentFull.into()));
+            }
+        };
+
+        // Write the block before pointing at it, so the seed is always valid
+        let mut write = || -> Result<()> {
+            let first_block: EncryptedBlock =
+                Block::new(self.block_size()).encrypt(&self.block_key(0)?, index, 0)?;
+            extent.write_block(index, &first_block)?;
+            let mut raw = extent.read_raw(sector)?;
+            seed_key.write(&mut raw, slot, index);
+            extent.write_raw(sector, &raw)
+        };
+        if let Err(e) = write() {
+            extent.release_seed_slot(sector, slot);
+            extent.deallocate_block(index);
+            return Err(e);
+        }
+
+        self.blocks.push(index);
+        self.seed = Some((sector, slot));
+        Ok(())
+    }
+
+    /// Return the block key for the given block id
+    pub fn block_key(&self, block_id: u64) -> Result<BlockKey> {
+        Ok(self.keyword.block_key(block_id)?)
+    }
+
+    /// Overwrite the payload of a block in the chain. The header is rewritten
+    /// so that the block keeps its place in the chain
+    pub fn write_block(&mut self, block_id: u64, mut content: Block) -> Result<()> {
+        if block_id >= self.len() {
+            return Err(Error::BadPointer.into());
+        }
+        let index = self.transform_block_id(block_id);
+        match self.blocks.get(block_id as usize + 1) {
+            Some(&next) => content.set_next_sector_id(next),
+            None => content.set_next_sector_id(END_OF_CHAIN),
+        }
+        content.update_checksum();
+        let block = content.encrypt(&self.block_key(block_id)?, index, block_id)?;
+        self.extent_handle.lock()?.write_block(index, &block)
+    }
+
+    /// Allocate a new, empty block at the end of the chain, returning its id
+    pub fn push_block(&mut self) -> Result<u64> {
+        let block_id = self.len();
+        let last = match self.blocks.last() {
+            Some(&last) => last,
+            None => return Err(Error::SeedNotFound.into()),
+        };
+        let index = self
+            .extent_handle
+            .lock()?
+            .alloc_block_for(&self.keyword, last, block_id)?
+            .ok_or(Error::ExtentFull)?;
+        self.blocks.push(index);
+        // Write the new block before linking it, so the chain is always valid
+        let linked = self
+            .write_block(block_id, Block::new(self.block_size()))
+            .and_then(|()| self.read_block(block_id - 1))
+            .and_then(|previous| self.write_block(block_id - 1, previous));
+        if let Err(e) = linked {
+            self.blocks.pop();
+            self.extent_handle.lock()?.deallocate_block(index);
+            return Err(e);
+        }
+        Ok(block_id)
+    }
+
+    /// Grow or shrink the chain to `n_blocks` blocks. New blocks are empty,
+    /// as after `push_block`, and blocks past the new end are released as by
+    /// `truncate`. The seed block can't be removed, so `n_blocks` must be at
+    /// least one
+    pub fn set_len(&mut self, n_blocks: u64) -> Result<()> {
+        if n_blocks == 0 {
+            return Err(Error::BadPointer.into());
+        }
+        while self.len() < n_blocks {
+            self.push_block()?;
+        }
+        self.truncate(n_blocks)
+    }
+
+    /// Shorten the chain to `n_blocks` blocks, doing nothing if it is no
+    /// longer than that
+    ///
+    /// The new last block is relinked to end the chain before the blocks cut
+    /// off are overwritten with random data and released, so an interruption
+    /// at any point leaves a valid chain. Use `destroy` to remove the seed
+    /// block as well.
+    pub fn truncate(&mut self, n_blocks: u64) -> Result<()> {
+        if n_blocks == 0 {
+            return Err(Error::BadPointer.into());
+        }
+        if n_blocks >= self.len() {
+            return Ok(());
+        }
+        let last = self.read_block(n_blocks - 1)?;
+        let tail = self.blocks.split_off(n_blocks as usize);
+        if let Err(e) = self.write_block(n_blocks - 1, last) {
+            self.blocks.extend(tail);
+            return Err(e);
+        }
+
+        let mut extent = self.extent_handle.lock()?;
+        let block_size = extent.device().block_size();
+        for index in tail {
+            extent.write_raw(index, &RawBlock::new_rand(block_size)?)?;
+            extent.deallocate_block(index);
+        }
+        Ok(())
+    }
+
+    /// Move the aspect to a new keyword, after which the old one no longer
+    /// finds it
+    ///
+    /// The chain is copied, block by block, to blocks allocated for the new
+    /// keyword and encrypted under its keys, and then a seed pointer for it is
+    /// written at its own seed index. Only then are the old seed slot and the
+    /// old blocks overwritten with random data and released. An interruption
+    /// before the new seed is written leaves the aspect as it was; after it,
+    /// the new keyword opens the aspect, and the old one may too until the
+    /// old seed slot has been erased. The extent needs room for a second copy
+    /// of the chain while this happens.
+    pub fn rekey(&mut self, keyword: Keyword) -> Result<()> {
+        let keyword = Arc::new(keyword);
+        let seed_key = SeedKey::new(&keyword, self.block_size())?;
+        let (old_sector, old_slot) = self.seed.ok_or(Error::SeedNotFound)?;
+        let mut extent = self.extent_handle.lock()?;
+        // Claiming a slot counts as unlocking an aspect, so check first
+        extent.check_guard()?;
+
+        let seed_index = keyword.seed_index(extent.n_blocks())?;
+        let (sector, slot) = extent
+            .alloc_seed_slot(seed_index, seed_key.candidates())
+            .ok_or(Error::ExtentFull)?;
+        let mut blocks = Vec::with_capacity(self.blocks.len());
+
+        // Nothing points at the copy until its seed is written
+        let mut copy = || -> Result<()> {
+            for block_id in 0..self.len() {
+                let previous = blocks.last().copied().unwrap_or(sector);
+                match extent.alloc_block_for(&keyword, previous, block_id)? {
+                    Some(index) => blocks.push(index),
+                    None => return Err(Error::ExtentFull.into()),
+                }
+            }
+            for (block_id, (&old, &new)) in self.blocks.iter().zip(&blocks).enumerate() {
+                let block_id = block_id as u64;
+                let mut block = extent
+                    .read_block(old)?
+                    .decrypt(&self.block_key(block_id)?, old, block_id)
+                    .map_err(chain_error)?;
+                if !block.validate_checksum() {
+                    return Err(Error::BadSuperBlock.into());
+                }
+                match blocks.get(block_id as usize + 1) {
+                    Some(&next) => block.set_next_sector_id(next),
+                    None => block.set_next_sector_id(END_OF_CHAIN),
+                }
+                block.update_checksum();
+                extent.write_block(
+                    new,
+                    &block.encrypt(&keyword.block_key(block_id)?, new, block_id)?,
+                )?;
+            }
+            let mut raw = extent.read_raw(sector)?;
+            seed_key.write(&mut raw, slot, blocks[0]);
+            extent.write_raw(sector, &raw)
+        };
+        if let Err(e) = copy() {
+            extent.release_seed_slot(sector, slot);
+            for &index in &blocks {
+                extent.deallocate_block(index);
+            }
+            return Err(e);
+        }
+
+        let old_blocks = std::mem::replace(&mut self.blocks, blocks);
+        self.keyword = keyword;
+        self.seed = Some((sector, slot));
+        Aspect::wipe(&mut extent, (old_sector, old_slot), &old_blocks)
+    }
+
+    /// Destroy the aspect, leaving the disk as if it had never existed
+    ///
+    /// The seed slot is erased first, so that nothing leads to the chain any
+    /// more, then every block of the chain is overwritten with random data and
+    /// released. Other aspects sharing the seed sector keep their slots. A
+    /// guarded extent expects one aspect fewer afterwards.
+    ///
+    /// Should this be interrupted, the aspect is gone as soon as its slot is:
+    /// the blocks not yet overwritten hold ciphertext that, with no seed left
+    /// to reach it, can't be told apart from the random blocks around it.
+    pub fn destroy(self) -> Result<()> {
+        let seed = self.seed.ok_or(Error::SeedNotFound)?;
+        let mut extent = self.extent_handle.lock()?;
+        Aspect::wipe(&mut extent, seed, &self.blocks)?;
+        extent.forget_aspect();
+        Ok(())
+    }
+
+    /// Erase a seed slot, then overwrite the blocks of the chain it pointed
+    /// to with random data, releasing every block and the slot. The slot is
+    /// only released once the chain is wiped, as it counts towards a guard on
+    /// the extent, which would otherwise refuse the remaining writes
+    fn wipe(
+        extent: &mut Extent<Device>,
+        (sector, slot): (u64, usize),
+        blocks: &[u64],
+    ) -> Result<()> {
+        let mut raw = extent.read_raw(sector)?;
+        SeedKey::erase(&mut raw, slot)?;
+        extent.write_raw(sector, &raw)?;
+        let block_size = extent.device().block_size();
+        for &index in blocks {
+            extent.write_raw(index, &RawBlock::new_rand(block_size)?)?;
+            extent.deallocate_block(index);
+        }
+        extent.release_seed_slot(sector, slot);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::allocator::Linear;
+    use crate::block::{
+        BLOCK_HEADER_SIZE, BLOCK_OVERHEAD, BLOCK_SIZE, MAX_BLOCK_SIZE, MIN_BLOCK_SIZE,
+    };
+    use crate::extent::{self, Chaff};
+    use crate::seed::SLOT_SIZE;
+    use crate::snapshot;
+    use crate::test_util::{extent_on, random_disk, random_extent};
+    use crate::{BlockDevice, RAMDisk, RawBlock};
+    use openssl::rand::rand_bytes;
+    use std::collections::HashSet;
+    use std::io;
+    use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+    use std::sync::Arc;
+
+    /// A copy of the extent's disk
+    fn copy_disk(extent: &ExtentHandle) -> RAMDisk {
+        let block_size = extent.block_size();
+        let mut extent = extent.lock().unwrap();
+        let mut disk = RAMDisk::with_block_size(extent.n_blocks() as usize, block_size);
+        for index in 0..extent.n_blocks() {
+            disk.write(index, &extent.read_raw(index).unwrap()).unwrap();
+        }
+        disk
+    }
+
+    /// A fresh handle onto a copy of the extent's disk, sharing no
+    /// allocation state with the original
+    fn reload(extent: &ExtentHandle) -> ExtentHandle {
+        extent_on(copy_disk(extent), None).into_handle()
+    }
+
+    fn random_u64() -> u64 {
+        let mut bytes = [0; 8];
+        rand_bytes(&mut bytes).unwrap();
+        u64::from_be_bytes(bytes)
+    }
+
+    /// Create an aspect holding `n_blocks` blocks, each tagged with `tag`
+    fn fill_aspect(extent: &ExtentHandle, text: &str, n_blocks: u64, tag: u8) -> Aspect {
+        let mut aspect = extent
+            .create_aspect(extent.keyword(text.to_string()).unwrap())
+            .unwrap();
+        for _ in 1..n_blocks {
+            aspect.push_block().unwrap();
+        }
+        for i in 0..n_blocks {
+            let mut block = Block::new(aspect.block_size());
+            block.data_mut()[0] = tag;
+            block.data_mut()[1] = i as u8;
+            aspect.write_block(i, block).unwrap();
+        }
+        aspect
+    }
+
+    fn check_aspect(extent: &ExtentHandle, text: &str, expected: &Aspect, tag: u8) {
+        let mut aspect = extent
+            .open_aspect(extent.keyword(text.to_string()).unwrap())
+            .unwrap();
+        assert_eq!(aspect.blocks(), expected.blocks());
+        for i in 0..aspect.len() {
+            let block = aspect.read_block(i).unwrap();
+            assert_eq!(&block.data()[..2], &[tag, i as u8]);
+        }
+    }
+
+    #[test]
+    fn reopen_seed_block() {
+        let extent = random_extent(16, BLOCK_SIZE, None);
+        let created = extent
+            .create_aspect(extent.keyword("hello".to_string()).unwrap())
+            .unwrap();
+        let opened = extent
+            .open_aspect(extent.keyword("hello".to_string()).unwrap())
+            .unwrap();
+        assert_eq!(opened.blocks(), created.blocks());
+    }
+
+    #[test]
+    fn missing_seed() {
+        let extent = random_extent(16, BLOCK_SIZE, None);
+        let result = extent.open_aspect(extent.keyword("hello".to_string()).unwrap());
+        assert_eq!(
+            result.err().and_then(|e| e.aspect()),
+            Some(Error::SeedNotFound)
+        );
+    }
+
+    #[test]
+    fn walk_chain() {
+        let extent = random_extent(16, BLOCK_SIZE, None);
+        let mut aspect = extent
+            .create_aspect(extent.keyword("hello".to_string()).unwrap())
+            .unwrap();
+        let seed = aspect.blocks()[0];
+        let chain = {
+            let mut extent = extent.lock().unwrap();
+            // Two free blocks, linked out of order and clear of the seed sector
+            let free: Vec<u64> = (0..16)
+                .filter(|&index| !extent.is_allocated(index))
+                .collect();
+            let chain = [seed, free[5], free[2]];
+            for (i, &index) in chain.iter().enumerate() {
+                let mut block = Block::new(aspect.block_size());
+                block.data_mut()[0] = i as u8;
+                if let Some(&next) = chain.get(i + 1) {
+                    block.set_next_sector_id(next);
+                }
+                block.update_checksum();
+                extent
+                    .write_block(
+                        index,
+                        &block
+                            .encrypt(&aspect.block_key(i as u64).unwrap(), index, i as u64)
+                            .unwrap(),
+                    )
+                    .unwrap();
+            }
+            chain
+        };
+
+        let mut opened =
+            Aspect::read_aspect(&extent, extent.keyword("hello".to_string()).unwrap()).unwrap();
+        assert_eq!(opened.blocks(), &chain);
+        for i in 0..chain.len() as u64 {
+            assert_eq!(opened.read_block(i).unwrap().data()[0], i as u8);
+        }
+        assert_eq!(
+            aspect.read_block(3).err().and_then(|e| e.aspect()),
+            Some(Error::BadPointer)
+        );
+    }
+
+    #[test]
+    fn corrupt_chain() {
+        let extent = random_extent(16, BLOCK_SIZE, None);
+        let aspect = extent
+            .create_aspect(extent.keyword("hello".to_string()).unwrap())
+            .unwrap();
+        let seed = aspect.blocks()[0];
+        {
+            let mut extent = extent.lock().unwrap();
+            let mut block = Block::new(aspect.block_size());
+            block.set_next_sector_id(100);
+            block.update_checksum();
+            extent
+                .write_block(
+                    seed,
+                    &block
+                        .encrypt(&aspect.block_key(0).unwrap(), seed, 0)
+                        .unwrap(),
+                )
+                .unwrap();
+        }
+        let result = extent.open_aspect(extent.keyword("hello".to_string()).unwrap());
+        assert_eq!(
+            result.err().and_then(|e| e.aspect()),
+            Some(Error::BadPointer)
+        );
+
+        {
+            let mut extent = extent.lock().unwrap();
+            let mut block = Block::new(aspect.block_size());
+            block.set_next_sector_id((seed + 1) % 16);
+            block.update_checksum();
+            extent
+                .write_block(
+                    seed,
+                    &block
+                        .encrypt(&aspect.block_key(0).unwrap(), seed, 0)
+                        .unwrap(),
+                )
+                .unwrap();
+        }
+        let result = extent.open_aspect(extent.keyword("hello".to_string()).unwrap());
+        assert_eq!(
+            result.err().and_then(|e| e.aspect()),
+            Some(Error::BadSuperBlock)
+        );
+
+        // A chain looping back on itself
+        {
+            let mut extent = extent.lock().unwrap();
+            let mut block = Block::new(aspect.block_size());
+            block.set_next_sector_id(seed);
+            block.update_checksum();
+            extent
+                .write_block(
+                    seed,
+                    &block
+                        .encrypt(&aspect.block_key(0).unwrap(), seed, 0)
+                        .unwrap(),
+                )
+                .unwrap();
+        }
+        let result = extent.open_aspect(extent.keyword("hello".to_string()).unwrap());
+        assert_eq!(
+            result.err().and_then(|e| e.aspect()),
+            Some(Error::BadPointer)
+        );
+    }
+
+    #[test]
+    fn tampered_chain() {
+        let extent = random_extent(16, BLOCK_SIZE, None);
+        let mut aspect = extent
+            .create_aspect(extent.keyword("hello".to_string()).unwrap())
+            .unwrap();
+        aspect.push_block().unwrap();
+        {
+            // Flip a bit in the middle of every block
+            let mut extent = extent.lock().unwrap();
+            for &index in aspect.blocks() {
+                let mut raw = extent.read_block(index).unwrap().as_ref().clone();
+                raw.as_mut()[500] ^= 1;
+                extent
+                    .write_block(index, &EncryptedBlock::from(raw))
+                    .unwrap();
+            }
+        }
+        assert_eq!(
+            aspect.read_block(1).err().and_then(|e| e.aspect()),
+            Some(Error::AuthenticationFailed)
+        );
+        let result = extent.open_aspect(extent.keyword("hello".to_string()).unwrap());
+        assert_eq!(
+            result.err().and_then(|e| e.aspect()),
+            Some(Error::AuthenticationFailed)
+        );
+    }
+
+    #[test]
+    fn push_and_write() {
+        let extent = random_extent(16, BLOCK_SIZE, None);
+        let mut aspect = extent
+            .create_aspect(extent.keyword("hello".to_string()).unwrap())
+            .unwrap();
+        for i in 1..4 {
+            assert_eq!(aspect.push_block().unwrap(), i);
+            let mut block = Block::new(aspect.block_size());
+            block.data_mut()[0] = i as u8;
+            aspect.write_block(i, block).unwrap();
+        }
+
+        let mut opened = extent
+            .open_aspect(extent.keyword("hello".to_string()).unwrap())
+            .unwrap();
+        assert_eq!(opened.blocks(), aspect.blocks());
+        for i in 1..4 {
+            assert_eq!(opened.read_block(i).unwrap().data()[0], i as u8);
+        }
+    }
+
+    /// Find `n` passphrases sharing a seed index other than the salt block
+    fn colliding_texts(extent: &ExtentHandle, n: usize) -> Vec<String> {
+        let n_blocks = extent.n_blocks();
+        let seed_index = |text: &String| {
+            extent
+                .keyword(text.clone())
+                .unwrap()
+                .seed_index(n_blocks)
+                .unwrap()
+        };
+        let candidates = (0..).map(|i: u32| i.to_string());
+        let target = candidates
+            .clone()
+            .map(|text| seed_index(&text))
+            .find(|&i| i != 0)
+            .unwrap();
+        candidates
+            .filter(|text| seed_index(text) == target)
+            .take(n)
+            .collect()
+    }
+
+    #[test]
+    fn colliding_seeds() {
+        let extent = random_extent(16, BLOCK_SIZE, None);
+        let texts = colliding_texts(&extent, 3);
+        let target = extent
+            .keyword(texts[0].clone())
+            .unwrap()
+            .seed_index(16)
+            .unwrap();
+
+        let aspects: Vec<Aspect> = texts
+            .iter()
+            .enumerate()
+            .map(|(i, text)| fill_aspect(&extent, text, 2, i as u8))
+            .collect();
+        // Every aspect shares the one seed sector
+        assert!(aspects.iter().all(|a| a.seed_sector() == Some(target)));
+
+        // Reopen in reverse order of creation
+        for (i, text) in texts.iter().enumerate().rev() {
+            check_aspect(&extent, text, &aspects[i], i as u8);
+            check_aspect(&reload(&extent), text, &aspects[i], i as u8);
+        }
+    }
+
+    #[test]
+    fn many_aspects() {
+        for _ in 0..8 {
+            let n_blocks = 32 + random_u64() % 32;
+            let extent = random_extent(n_blocks as usize, BLOCK_SIZE, None);
+            let mut aspects = Vec::new();
+            let mut used = 1;
+            loop {
+                // Leave room for a seed sector, which may end up shared
+                let len = 1 + random_u64() % 4;
+                if used + len + 1 > n_blocks {
+                    break;
+                }
+                let text = format!("aspect {}", random_u64());
+                let tag = aspects.len() as u8;
+                aspects.push((fill_aspect(&extent, &text, len, tag), text));
+                used += len + 1;
+            }
+            assert!(extent.lock().unwrap().free_blocks() >= n_blocks - used);
+
+            let reloaded = reload(&extent);
+            for (i, (aspect, text)) in aspects.iter().enumerate() {
+                check_aspect(&extent, text, aspect, i as u8);
+                check_aspect(&reloaded, text, aspect, i as u8);
+            }
+        }
+    }
+
+    #[test]
+    fn shared_seed_sector() {
+        let extent = random_extent(16, BLOCK_SIZE, None);
+        let texts = colliding_texts(&extent, 2);
+        let first = fill_aspect(&extent, &texts[0], 1, 0);
+        let sector = first.seed_sector().unwrap();
+        let before = extent.lock().unwrap().read_raw(sector).unwrap();
+
+        let second = fill_aspect(&extent, &texts[1], 1, 1);
+        assert_eq!(second.seed_sector(), Some(sector));
+        let after = extent.lock().unwrap().read_raw(sector).unwrap();
+        let changed_slots: HashSet<usize> = (0..BLOCK_SIZE)
+            .filter(|&i| before.as_ref()[i] != after.as_ref()[i])
+            .map(|i| i / SLOT_SIZE)
+            .collect();
+        assert_eq!(changed_slots.len(), 1);
+
+        let reloaded = reload(&extent);
+        check_aspect(&reloaded, &texts[0], &first, 0);
+        check_aspect(&reloaded, &texts[1], &second, 1);
+    }
+
+    /// Push blocks onto an aspect until the extent runs out
+    fn fill_extent(aspect: &mut Aspect) {
+        loop {
+            match aspect.push_block() {
+                Ok(_) => (),
+                Err(e) => return assert_eq!(e.aspect(), Some(Error::ExtentFull)),
+            }
+        }
+    }
+
+    #[test]
+    fn allocation_layout() {
+        let extent = random_extent(256, BLOCK_SIZE, None);
+        let scattered = fill_aspect(&extent, "hello", 16, 0);
+        let adjacent = scattered
+            .blocks()
+            .windows(2)
+            .filter(|w| w[1] == w[0] + 1)
+            .count();
+        assert!(adjacent < 4, "{:?}", scattered.blocks());
+        check_aspect(&reload(&extent), "hello", &scattered, 0);
+
+        let extent = extent_on(random_disk(256, BLOCK_SIZE), None)
+            .with_allocator(Linear)
+            .into_handle();
+        let contiguous = fill_aspect(&extent, "hello", 16, 0);
+        let mut previous = contiguous.seed_sector().unwrap();
+        for &index in contiguous.blocks() {
+            // Only the salt block is skipped when wrapping around
+            assert!(
+                (index + 256 - previous) % 256 <= 2,
+                "{:?}",
+                contiguous.blocks()
+            );
+            previous = index;
+        }
+    }
+
+    #[test]
+    fn chaff_reencrypts_owned_blocks() {
+        let chaff = Chaff {
+            free_blocks: 0,
+            owned_blocks: 4,
+        };
+        let extent = extent_on(random_disk(16, BLOCK_SIZE), None)
+            .with_chaff(chaff)
+            .into_handle();
+        let aspect = fill_aspect(&extent, "hello", 4, 7);
+        let before = copy_disk(&extent);
+
+        extent.lock().unwrap().flush().unwrap();
+        let mut after = copy_disk(&extent);
+        for index in 0..16 {
+            let changed = before.clone().read(index).unwrap() != after.read(index).unwrap();
+            assert_eq!(changed, aspect.blocks().contains(&index), "block {}", index);
+        }
+        check_aspect(&reload(&extent), "hello", &aspect, 7);
+    }
+
+    #[test]
+    fn rebuilt_allocation() {
+        let extent = random_extent(16, BLOCK_SIZE, None);
+        let first = fill_aspect(&extent, "first", 3, 0);
+        let second = fill_aspect(&extent, "second", 3, 1);
+
+        // With both aspects unlocked after a restart, neither is overwritten
+        let reloaded = reload(&extent);
+        let mut opened = reloaded
+            .open_aspect(reloaded.keyword("first".to_string()).unwrap())
+            .unwrap();
+        reloaded
+            .open_aspect(reloaded.keyword("second".to_string()).unwrap())
+            .unwrap();
+        fill_extent(&mut opened);
+        assert_eq!(reloaded.lock().unwrap().free_blocks(), 0);
+        check_aspect(&reloaded, "second", &second, 1);
+
+        // An aspect that isn't unlocked looks like free space
+        let reloaded = reload(&extent);
+        let mut opened = reloaded
+            .open_aspect(reloaded.keyword("first".to_string()).unwrap())
+            .unwrap();
+        fill_extent(&mut opened);
+        assert!(reloaded
+            .open_aspect(reloaded.keyword("second".to_string()).unwrap())
+            .is_err());
+        assert_eq!(&opened.blocks()[..3], first.blocks());
+    }
+
+    #[test]
+    fn guard_until_unlocked() {
+        let extent = random_extent(16, BLOCK_SIZE, None);
+        fill_aspect(&extent, "first", 2, 0);
+        let second = fill_aspect(&extent, "second", 2, 1);
+
+        let guarded = extent_on(copy_disk(&extent), Some(2)).into_handle();
+        let mut first = guarded
+            .open_aspect(guarded.keyword("first".to_string()).unwrap())
+            .unwrap();
+        let guard_error =
+            |e: crate::Error| matches!(e, crate::Error::Extent(extent::Error::Guarded));
+        assert!(first.push_block().is_err_and(guard_error));
+        assert!(first
+            .write_block(0, Block::new(BLOCK_SIZE))
+            .is_err_and(guard_error));
+        assert!(guarded
+            .create_aspect(guarded.keyword("third".to_string()).unwrap())
+            .is_err_and(guard_error));
+        assert_eq!(first.len(), 2);
+        assert_eq!(guarded.lock().unwrap().free_blocks(), 12);
+
+        guarded
+            .open_aspect(guarded.keyword("second".to_string()).unwrap())
+            .unwrap();
+        assert_eq!(first.push_block().unwrap(), 2);
+        guarded
+            .create_aspect(guarded.keyword("third".to_string()).unwrap())
+            .unwrap();
+        assert_eq!(guarded.lock().unwrap().unlocked_aspects(), 3);
+        check_aspect(&guarded, "second", &second, 1);
+    }
+
+    /// A RAM disk whose reads start failing when asked to
+    #[derive(Debug)]
+    struct FailingDisk(RAMDisk, Arc<AtomicBool>);
+
+    impl BlockDevice for FailingDisk {
+        fn read(&mut self, index: u64) -> crate::Result<RawBlock> {
+            match self.1.load(Ordering::Relaxed) {
+                true => Err(io::Error::other("bad sector").into()),
+                false => self.0.read(index),
+            }
+        }
+
+        fn write(&mut self, index: u64, block: &RawBlock) -> crate::Result<()> {
+            self.0.write(index, block)
+        }
+
+        fn len(&self) -> crate::Result<u64> {
+            self.0.len()
+        }
+
+        fn block_size(&self) -> usize {
+            self.0.block_size()
+        }
+    }
+
+    /// A RAM disk that stops taking writes after a number of them, as if the
+    /// machine had crashed
+    #[derive(Debug)]
+    struct CrashingDisk(RAMDisk, Arc<AtomicU64>);
+
+    impl BlockDevice for CrashingDisk {
+        fn read(&mut self, index: u64) -> crate::Result<RawBlock> {
+            self.0.read(index)
+        }
+
+        fn write(&mut self, index: u64, block: &RawBlock) -> crate::Result<()> {
+            match self
+                .1
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1))
+            {
+                Ok(_) => self.0.write(index, block),
+                Err(_) => Err(io::Error::other("crashed").into()),
+            }
+        }
+
+        fn len(&self) -> crate::Result<u64> {
+            self.0.len()
+        }
+
+        fn block_size(&self) -> usize {
+            self.0.block_size()
+        }
+    }
+
+    /// Whether the aspect opens and holds `n_blocks` blocks tagged with `tag`
+    fn holds(extent: &ExtentHandle, text: &str, n_blocks: u64, tag: u8) -> bool {
+        let Ok(mut aspect) = extent.open_aspect(extent.keyword(text.to_string()).unwrap()) else {
+            return false;
+        };
+        aspect.len() == n_blocks
+            && (0..n_blocks).all(|i| {
+                aspect
+                    .read_block(i)
+                    .is_ok_and(|block| block.data()[..2] == [tag, i as u8])
+            })
+    }
+
+    #[test]
+    fn rekey_moves_aspect() {
+        let extent = random_extent(32, BLOCK_SIZE, None);
+        let mut aspect = fill_aspect(&extent, "old", 4, 3);
+        let other = fill_aspect(&extent, "other", 2, 9);
+        let old_blocks = aspect.blocks().to_vec();
+        aspect
+            .rekey(extent.keyword("new".to_string()).unwrap())
+            .unwrap();
+        assert!(aspect
+            .blocks()
+            .iter()
+            .all(|index| !old_blocks.contains(index)));
+        check_aspect(&extent, "new", &aspect, 3);
+
+        let reloaded = reload(&extent);
+        check_aspect(&reloaded, "new", &aspect, 3);
+        check_aspect(&reloaded, "other", &other, 9);
+        let old = reloaded.keyword("old".to_string()).unwrap();
+        let result = reloaded.open_aspect(reloaded.keyword("old".to_string()).unwrap());
+        assert_eq!(
+            result.err().and_then(|e| e.aspect()),
+            Some(Error::SeedNotFound)
+        );
+        // The old blocks were wiped, not just abandoned
+        let mut reloaded = reloaded.lock().unwrap();
+        for (i, &index) in old_blocks.iter().enumerate() {
+            let block = reloaded.read_block(index).unwrap();
+            assert!(block
+                .decrypt(&old.block_key(i as u64).unwrap(), index, i as u64)
+                .is_err());
+        }
+    }
+
+    #[test]
+    fn destroy_aspect() {
+        let extent = random_extent(32, BLOCK_SIZE, None);
+        let texts = colliding_texts(&extent, 2);
+        let free = extent.lock().unwrap().free_blocks();
+        let doomed = fill_aspect(&extent, &texts[0], 4, 1);
+        let survivor = fill_aspect(&extent, &texts[1], 3, 2);
+        let sector = doomed.seed_sector().unwrap();
+        let doomed_blocks = doomed.blocks().to_vec();
+        let mut before = copy_disk(&extent);
+
+        doomed.destroy().unwrap();
+        let mut after = copy_disk(&extent);
+        // Every block went back to the allocator, bar the shared seed sector
+        assert_eq!(extent.lock().unwrap().free_blocks(), free - 4);
+        let report = snapshot::diff(&mut before, &mut after, None).unwrap();
+        let mut expected = doomed_blocks.clone();
+        expected.push(sector);
+        expected.sort();
+        assert_eq!(report.changed, expected);
+        assert!(report.min_entropy.unwrap() > 7.5);
+
+        let reloaded = reload(&extent);
+        let result = reloaded.open_aspect(reloaded.keyword(texts[0].clone()).unwrap());
+        assert_eq!(
+            result.err().and_then(|e| e.aspect()),
+            Some(Error::SeedNotFound)
+        );
+        check_aspect(&reloaded, &texts[1], &survivor, 2);
+    }
+
+    #[test]
+    fn destroy_guarded_aspect() {
+        let extent = random_extent(32, BLOCK_SIZE, None);
+        fill_aspect(&extent, "doomed", 4, 1);
+        fill_aspect(&extent, "survivor", 3, 2);
+        let guarded = extent_on(copy_disk(&extent), Some(2)).into_handle();
+        let doomed = guarded
+            .open_aspect(guarded.keyword("doomed".to_string()).unwrap())
+            .unwrap();
+        let mut kept = guarded
+            .open_aspect(guarded.keyword("survivor".to_string()).unwrap())
+            .unwrap();
+        let shared = doomed.seed_sector() == kept.seed_sector();
+        let free = guarded.lock().unwrap().free_blocks();
+        let mut before = copy_disk(&guarded);
+
+        doomed.destroy().unwrap();
+        let released = if shared { 4 } else { 5 };
+        assert_eq!(guarded.lock().unwrap().free_blocks(), free + released);
+        let report = snapshot::diff(&mut before, &mut copy_disk(&guarded), None).unwrap();
+        assert_eq!(report.changed.len(), 5);
+        assert!(report.min_entropy.unwrap() > 7.5);
+        // The guard now expects the one aspect left
+        kept.push_block().unwrap();
+
+        let reloaded = reload(&guarded);
+        let result = reloaded.open_aspect(reloaded.keyword("doomed".to_string()).unwrap());
+        assert_eq!(
+            result.err().and_then(|e| e.aspect()),
+            Some(Error::SeedNotFound)
+        );
+        let mut reopened = reloaded
+            .open_aspect(reloaded.keyword("survivor".to_string()).unwrap())
+            .unwrap();
+        assert_eq!(reopened.len(), 4);
+        for i in 0..3 {
+            assert_eq!(reopened.read_block(i).unwrap().data()[..2], [2, i as u8]);
+        }
+    }
+
+    #[test]
+    fn destroy_survives_crashes() {
+        let base = random_extent(32, BLOCK_SIZE, None);
+        fill_aspect(&base, "doomed", 4, 1);
+        let survivor = fill_aspect(&base, "survivor", 3, 2);
+        // The seed slot, then four wiped blocks
+        for writes in 0..=5 {
+            let left = Arc::new(AtomicU64::new(writes));
+            let extent = extent_on(CrashingDisk(copy_disk(&base), left), None).into_handle();
+            let doomed = extent
+                .open_aspect(extent.keyword("doomed".to_string()).unwrap())
+                .unwrap();
+            assert_eq!(doomed.destroy().is_ok(), writes == 5);
+
+            // Once its slot is erased the aspect is gone, however much of
+            // the chain is left
+            let reloaded = reload(&extent);
+            assert_eq!(
+                holds(&reloaded, "doomed", 4, 1),
+                writes == 0,
+                "after {} writes",
+                writes
+            );
+            check_aspect(&reloaded, "survivor", &survivor, 2);
+        }
+    }
+
+    #[test]
+    fn truncate_aspect() {
+        let extent = random_extent(32, BLOCK_SIZE, None);
+        let free = extent.lock().unwrap().free_blocks();
+        let mut aspect = fill_aspect(&extent, "hello", 6, 1);
+        let tail = aspect.blocks()[2..].to_vec();
+        let mut before = copy_disk(&extent);
+
+        aspect.truncate(2).unwrap();
+        assert_eq!(aspect.len(), 2);
+        assert_eq!(extent.lock().unwrap().free_blocks(), free - 3);
+        // The new last block and the released tail changed, nothing else
+        let report = snapshot::diff(&mut before, &mut copy_disk(&extent), None).unwrap();
+        let mut expected = tail;
+        expected.push(aspect.blocks()[1]);
+        expected.sort();
+        assert_eq!(report.changed, expected);
+        assert!(report.min_entropy.unwrap() > 7.5);
+        assert!(holds(&reload(&extent), "hello", 2, 1));
+
+        aspect.truncate(4).unwrap();
+        assert_eq!(aspect.len(), 2);
+        assert_eq!(
+            aspect.truncate(0).err().and_then(|e| e.aspect()),
+            Some(Error::BadPointer)
+        );
+    }
+
+    #[test]
+    fn set_aspect_len() {
+        let extent = random_extent(32, BLOCK_SIZE, None);
+        let free = extent.lock().unwrap().free_blocks();
+        let mut aspect = fill_aspect(&extent, "hello", 3, 1);
+
+        aspect.set_len(5).unwrap();
+        assert_eq!(aspect.len(), 5);
+        assert_eq!(extent.lock().unwrap().free_blocks(), free - 6);
+        let reloaded = reload(&extent);
+        let mut reopened = reloaded
+            .open_aspect(reloaded.keyword("hello".to_string()).unwrap())
+            .unwrap();
+        assert_eq!(reopened.blocks(), aspect.blocks());
+        assert!(reopened
+            .read_block(4)
+            .unwrap()
+            .data()
+            .iter()
+            .all(|&b| b == 0));
+
+        aspect.set_len(1).unwrap();
+        assert_eq!(extent.lock().unwrap().free_blocks(), free - 2);
+        assert!(holds(&reload(&extent), "hello", 1, 1));
+        assert!(aspect.set_len(0).is_err());
+        assert_eq!(aspect.len(), 1);
+    }
+
+    #[test]
+    fn rekey_without_space() {
+        let extent = random_extent(8, BLOCK_SIZE, None);
+        let mut aspect = fill_aspect(&extent, "old", 4, 3);
+        let result = aspect.rekey(extent.keyword("new".to_string()).unwrap());
+        assert_eq!(
+            result.err().and_then(|e| e.aspect()),
+            Some(Error::ExtentFull)
+        );
+        assert_eq!(extent.lock().unwrap().free_blocks(), 2);
+        assert!(holds(&extent, "old", 4, 3));
+        assert!(holds(&reload(&extent), "old", 4, 3));
+        assert!(!holds(&reload(&extent), "new", 4, 3));
+    }
+
+    #[test]
+    fn rekey_survives_crashes() {
+        let base = random_extent(32, BLOCK_SIZE, None);
+        fill_aspect(&base, "old", 3, 5);
+        // Three copied blocks, the new seed, the old seed and three wiped blocks
+        for writes in 0..=8 {
+            let left = Arc::new(AtomicU64::new(writes));
+            let extent = extent_on(CrashingDisk(copy_disk(&base), left), None).into_handle();
+            let mut aspect = extent
+                .open_aspect(extent.keyword("old".to_string()).unwrap())
+                .unwrap();
+            let result = aspect.rekey(extent.keyword("new".to_string()).unwrap());
+            assert_eq!(result.is_ok(), writes == 8);
+
+            let survivor = reload(&extent);
+            let (old, new) = (holds(&survivor, "old", 3, 5), holds(&survivor, "new", 3, 5));
+            assert!(old || new, "aspect lost after {} writes", writes);
+            assert_eq!(new, writes >= 4, "after {} writes", writes);
+            assert_eq!(old, writes <= 4, "after {} writes", writes);
+        }
+    }
+
+    #[test]
+    fn device_errors() {
+        let failing = Arc::new(AtomicBool::new(false));
+        let disk = FailingDisk(RAMDisk::new(16), failing.clone());
+        let extent = extent_on(disk, None).into_handle();
+        let mut aspect = extent
+            .create_aspect(extent.keyword("hello".to_string()).unwrap())
+            .unwrap();
+
+        failing.store(true, Ordering::Relaxed);
+        assert!(matches!(aspect.read_block(0), Err(crate::Error::Io(_))));
+        assert!(matches!(aspect.push_block(), Err(crate::Error::Io(_))));
+        let result = extent.open_aspect(extent.keyword("hello".to_string()).unwrap());
+        assert!(matches!(result, Err(crate::Error::Io(_))));
+
+        // The failed push left the chain and the allocator as they were
+        failing.store(false, Ordering::Relaxed);
+        assert_eq!(aspect.len(), 1);
+        assert_eq!(extent.lock().unwrap().free_blocks(), 13);
+    }
+
+    #[test]
+    fn block_sizes() {
+        for block_size in [MIN_BLOCK_SIZE, 4096, MAX_BLOCK_SIZE] {
+            let extent = random_extent(16, block_size, None);
+            assert_eq!(extent.block_size(), block_size);
+            let first = fill_aspect(&extent, "first", 3, 0);
+            let second = fill_aspect(&extent, "second", 4, 1);
+            assert_eq!(
+                first.data_size(),
+                block_size - BLOCK_HEADER_SIZE - BLOCK_OVERHEAD
+            );
+
+            let reloaded = reload(&extent);
+            check_aspect(&reloaded, "first", &first, 0);
+            check_aspect(&reloaded, "second", &second, 1);
+        }
+    }
+
+    #[test]
+    fn full_extent() {
+        let extent = random_extent(4, BLOCK_SIZE, None);
+        let mut aspect = fill_aspect(&extent, "hello", 2, 0);
+        assert_eq!(
+            aspect.push_block().err().and_then(|e| e.aspect()),
+            Some(Error::ExtentFull)
+        );
+        let result = extent.create_aspect(extent.keyword("other".to_string()).unwrap());
+        assert_eq!(
+            result.err().and_then(|e| e.aspect()),
+            Some(Error::ExtentFull)
+        );
+        check_aspect(&extent, "hello", &aspect, 0);
+    }
+}
@@ -0,0 +1,158 @@
+use std::fmt;
+
+use super::{EncryptedBlock, BLOCK_OVERHEAD};
+use crate::crc::crc64;
+use crate::error::Result;
+use crate::keyword::BlockKey;
+
+/// Bytes at the start of every block holding the chain pointer and checksum
+pub const BLOCK_HEADER_SIZE: usize = 16;
+
+/// Sentinel stored in `next_sector_id` by the last block of a chain
+pub const END_OF_CHAIN: u64 = u64::MAX;
+
+const NEXT_SECTOR_ID: std::ops::Range<usize> = 0..8;
+const CHECKSUM: std::ops::Range<usize> = 8..16;
+
+/// The plaintext of a block, laid out as
+///
+/// | next sector id | checksum | data |
+///
+/// The header fields are little-endian. The whole block is kept as one buffer
+/// so it can be encrypted and decrypted in place, without reinterpreting it.
+/// It is [`BLOCK_OVERHEAD`] bytes shorter than the blocks on disk.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Block(Box<[u8]>);
+
+impl Block {
+    /// An empty last block, for an extent with `block_size` byte blocks
+    pub fn new(block_size: usize) -> Block {
+        let mut block = Block::zeroed(block_size);
+        block.set_next_sector_id(END_OF_CHAIN);
+        block.update_checksum();
+        block
+    }
+
+    /// An all-zero block, to be filled in by decryption
+    pub(super) fn zeroed(block_size: usize) -> Block {
+        Block(vec![0; block_size - BLOCK_OVERHEAD].into_boxed_slice())
+    }
+
+    /// Bytes of data carried by each block of an extent with `block_size`
+    /// byte blocks
+    pub const fn data_size(block_size: usize) -> usize {
+        block_size - BLOCK_OVERHEAD - BLOCK_HEADER_SIZE
+    }
+
+    /// Size of the block once encrypted
+    pub fn block_size(&self) -> usize {
+        self.0.len() + BLOCK_OVERHEAD
+    }
+
+    fn header_field(&self, range: std::ops::Range<usize>) -> u64 {
+        u64::from_le_bytes(self.0[range].try_into().unwrap())
+    }
+
+    /// The sector holding the next block in the chain
+    pub fn next_sector_id(&self) -> u64 {
+        self.header_field(NEXT_SECTOR_ID)
+    }
+
+    pub fn set_next_sector_id(&mut self, next_sector_id: u64) {
+        self.0[NEXT_SECTOR_ID].copy_from_slice(&next_sector_id.to_le_bytes());
+    }
+
+    /// True if this is the final block of its chain
+    pub fn is_last(&self) -> bool {
+        self.next_sector_id() == END_OF_CHAIN
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.0[BLOCK_HEADER_SIZE..]
+    }
+
+    /// Mutable access to the payload. Call `update_checksum` once finished
+    pub fn data_mut(&mut self) -> &mut [u8] {
+        &mut self.0[BLOCK_HEADER_SIZE..]
+    }
+
+    /// Calculate the checksum for the block
+    /// ECMA-182 based CRC64
+    fn calculate_checksum(&self) -> u64 {
+        crc64(self.data())
+    }
+
+    pub fn update_checksum(&mut self) {
+        let checksum = self.calculate_checksum();
+        self.0[CHECKSUM].copy_from_slice(&checksum.to_le_bytes());
+    }
+
+    /// Tests if the checksum is valid
+    pub fn validate_checksum(&self) -> bool {
+        self.calculate_checksum() == self.header_field(CHECKSUM)
+    }
+
+    /// Encrypt the block for storage at `index`, as block `position` of its
+    /// chain. A fresh nonce is drawn every time
+    pub fn encrypt(&self, key: &BlockKey, index: u64, position: u64) -> Result<EncryptedBlock> {
+        EncryptedBlock::seal(self, key, index, position)
+    }
+}
+
+impl AsRef<[u8]> for Block {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl AsMut<[u8]> for Block {
+    fn as_mut(&mut self) -> &mut [u8] {
+        &mut self.0
+    }
+}
+
+impl fmt::Debug for Block {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Block")
+            .field("next_sector_id", &self.next_sector_id())
+            .field("checksum", &self.header_field(CHECKSUM))
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::{BLOCK_SIZE, MAX_BLOCK_SIZE, MIN_BLOCK_SIZE};
+
+    #[test]
+    fn block_size() {
+        for block_size in [MIN_BLOCK_SIZE, BLOCK_SIZE, MAX_BLOCK_SIZE] {
+            let block = Block::new(block_size);
+            assert_eq!(block.block_size(), block_size);
+            assert_eq!(block.data().len(), Block::data_size(block_size));
+        }
+    }
+
+    #[test]
+    fn crc_sanity() {
+        assert_eq!(crc64(&[0x00, 0x01, 0x02, 0x04]), 8513814196102790297);
+    }
+
+    #[test]
+    fn header_layout() {
+        let mut block = Block::new(BLOCK_SIZE);
+        assert!(block.is_last());
+        assert!(block.validate_checksum());
+        block.set_next_sector_id(0x0102_0304_0506_0708);
+        block.data_mut()[0] = 0xff;
+        assert!(!block.validate_checksum());
+        block.update_checksum();
+        assert!(block.validate_checksum());
+
+        let bytes = block.as_ref();
+        assert_eq!(bytes[..8], [8, 7, 6, 5, 4, 3, 2, 1]);
+        assert_eq!(bytes[8..16], crc64(block.data()).to_le_bytes());
+        assert_eq!(bytes[BLOCK_HEADER_SIZE], 0xff);
+    }
+}
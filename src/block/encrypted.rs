@@ -0,0 +1,313 @@
+use openssl::memcmp;
+use openssl::rand::rand_bytes;
+use openssl::symm::{Cipher, Crypter, Mode};
+use std::fmt;
+
+use super::{Block, RawBlock};
+use crate::error::Result;
+use crate::keyword::{hmac, BlockKey};
+use crate::Key;
+
+const NONCE_SIZE: usize = 16;
+const OWNER_TAG_SIZE: usize = 8;
+const MAC_SIZE: usize = 16;
+
+/// Bytes of every block taken up by the nonce and authentication tags
+pub const BLOCK_OVERHEAD: usize = NONCE_SIZE + OWNER_TAG_SIZE + MAC_SIZE;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The block was not written with this key at this chain position
+    NotMyBlock,
+    /// The block belongs to this key, but has been modified or moved
+    AuthenticationFailed,
+}
+
+/// A block as stored on disk, laid out as
+///
+/// | nonce | ciphertext | owner tag | mac |
+///
+/// The ciphertext is the `Block` encrypted with AES-256-XTS, tweaked with the
+/// random nonce. The owner tag is a MAC over the nonce and chain position, and
+/// tells a reader whether the block is theirs at all. The mac covers the whole
+/// block along with its index on disk and its position in the chain. Every
+/// field is either random or the output of a keyed function, so the block can
+/// not be told apart from random fill.
+pub struct EncryptedBlock(RawBlock);
+
+impl EncryptedBlock {
+    fn ciphertext_range(&self) -> std::ops::Range<usize> {
+        NONCE_SIZE..self.0.len() - OWNER_TAG_SIZE - MAC_SIZE
+    }
+
+    fn owner_tag_range(&self) -> std::ops::Range<usize> {
+        self.0.len() - OWNER_TAG_SIZE - MAC_SIZE..self.0.len() - MAC_SIZE
+    }
+
+    fn mac_range(&self) -> std::ops::Range<usize> {
+        self.0.len() - MAC_SIZE..self.0.len()
+    }
+
+    fn nonce(&self) -> &[u8] {
+        &self.0.as_ref()[..NONCE_SIZE]
+    }
+
+    fn ciphertext(&self) -> &[u8] {
+        &self.0.as_ref()[self.ciphertext_range()]
+    }
+
+    fn owner_tag(&self) -> &[u8] {
+        &self.0.as_ref()[self.owner_tag_range()]
+    }
+
+    fn mac(&self) -> &[u8] {
+        &self.0.as_ref()[self.mac_range()]
+    }
+
+    fn expected_owner_tag(mac_key: &Key, nonce: &[u8], position: u64) -> Result<Key> {
+        Ok(hmac(mac_key, &[b"owner", nonce, &position.to_be_bytes()])?)
+    }
+
+    fn expected_mac(
+        mac_key: &Key,
+        nonce: &[u8],
+        ciphertext: &[u8],
+        index: u64,
+        position: u64,
+    ) -> Result<Key> {
+        Ok(hmac(
+            mac_key,
+            &[
+                b"mac",
+                &index.to_be_bytes(),
+                &position.to_be_bytes(),
+                nonce,
+                ciphertext,
+            ],
+        )?)
+    }
+
+    /// Run AES-256-XTS over `input` in a single data unit, writing straight
+    /// into `output`, which must be at least as long
+    fn xts(
+        mode: Mode,
+        key: &BlockKey,
+        nonce: &[u8],
+        input: &[u8],
+        output: &mut [u8],
+    ) -> Result<()> {
+        let mut crypter = Crypter::new(Cipher::aes_256_xts(), mode, key.cipher(), Some(nonce))?;
+        let n = crypter.update(input, output)?;
+        crypter.finalize(&mut output[n..])?;
+        Ok(())
+    }
+
+    /// Encrypt `block` for storage at `index`, as block `position` of its
+    /// chain. A fresh nonce is drawn every time
+    pub(super) fn seal(
+        block: &Block,
+        key: &BlockKey,
+        index: u64,
+        position: u64,
+    ) -> Result<EncryptedBlock> {
+        let plaintext = block.as_ref();
+        let mut raw = RawBlock::new(block.block_size());
+        let bytes = raw.as_mut();
+        rand_bytes(&mut bytes[..NONCE_SIZE])?;
+        let (nonce, rest) = bytes.split_at_mut(NONCE_SIZE);
+        EncryptedBlock::xts(
+            Mode::Encrypt,
+            key,
+            nonce,
+            plaintext,
+            &mut rest[..plaintext.len()],
+        )?;
+
+        let mut block = EncryptedBlock(raw);
+        let owner_tag = EncryptedBlock::expected_owner_tag(key.mac(), block.nonce(), position)?;
+        let mac = EncryptedBlock::expected_mac(
+            key.mac(),
+            block.nonce(),
+            block.ciphertext(),
+            index,
+            position,
+        )?;
+        let (owner_tag_range, mac_range) = (block.owner_tag_range(), block.mac_range());
+        let bytes = block.0.as_mut();
+        bytes[owner_tag_range].copy_from_slice(&owner_tag[..OWNER_TAG_SIZE]);
+        bytes[mac_range].copy_from_slice(&mac[..MAC_SIZE]);
+        Ok(block)
+    }
+
+    /// Authenticate and decrypt the block stored at `index`, expected to be
+    /// block `position` of its chain
+    ///
+    /// Blocks of other keys or positions fail with [`Error::NotMyBlock`],
+    /// wrapped in [`crate::Error::Block`], and tampered blocks with
+    /// [`Error::AuthenticationFailed`].
+    pub fn decrypt(&self, key: &BlockKey, index: u64, position: u64) -> Result<Block> {
+        let owner_tag = EncryptedBlock::expected_owner_tag(key.mac(), self.nonce(), position)?;
+        if !memcmp::eq(&owner_tag[..OWNER_TAG_SIZE], self.owner_tag()) {
+            return Err(Error::NotMyBlock.into());
+        }
+        let mac = EncryptedBlock::expected_mac(
+            key.mac(),
+            self.nonce(),
+            self.ciphertext(),
+            index,
+            position,
+        )?;
+        if !memcmp::eq(&mac[..MAC_SIZE], self.mac()) {
+            return Err(Error::AuthenticationFailed.into());
+        }
+
+        let mut block = Block::zeroed(self.0.len());
+        EncryptedBlock::xts(
+            Mode::Decrypt,
+            key,
+            self.nonce(),
+            self.ciphertext(),
+            block.as_mut(),
+        )?;
+        Ok(block)
+    }
+
+    /// The bytes to be written to disk
+    pub fn into_raw(self) -> RawBlock {
+        self.0
+    }
+}
+
+impl From<RawBlock> for EncryptedBlock {
+    fn from(block: RawBlock) -> EncryptedBlock {
+        EncryptedBlock(block)
+    }
+}
+
+impl From<EncryptedBlock> for RawBlock {
+    fn from(block: EncryptedBlock) -> RawBlock {
+        block.0
+    }
+}
+
+impl AsRef<RawBlock> for EncryptedBlock {
+    fn as_ref(&self) -> &RawBlock {
+        &self.0
+    }
+}
+
+impl fmt::Debug for EncryptedBlock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:02x?}", self.0.as_ref())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::{BLOCK_SIZE, MAX_BLOCK_SIZE, MIN_BLOCK_SIZE};
+
+    fn test_key(seed: u8) -> BlockKey {
+        BlockKey::derive(&[seed; 32], b"test").unwrap()
+    }
+
+    fn sample_block() -> Block {
+        let mut block = Block::new(BLOCK_SIZE);
+        block.data_mut()[..5].copy_from_slice(b"hello");
+        block.set_next_sector_id(3);
+        block.update_checksum();
+        block
+    }
+
+    #[test]
+    fn encrypt_round_trip() {
+        let key = &test_key(7);
+        let block = sample_block()
+            .encrypt(key, 9, 2)
+            .unwrap()
+            .decrypt(key, 9, 2)
+            .unwrap();
+        assert!(block.validate_checksum());
+        assert_eq!(block.next_sector_id(), 3);
+        assert_eq!(&block.data()[..5], b"hello");
+    }
+
+    #[test]
+    fn raw_round_trip() {
+        let key = &test_key(7);
+        let sealed = sample_block().encrypt(key, 1, 0).unwrap();
+        let bytes = sealed.as_ref().as_ref().to_vec();
+        let raw = sealed.into_raw();
+        assert_eq!(raw.as_ref(), &bytes[..]);
+        let block = EncryptedBlock::from(raw).decrypt(key, 1, 0).unwrap();
+        assert_eq!(&block.data()[..5], b"hello");
+    }
+
+    #[test]
+    fn fresh_nonce_per_write() {
+        let key = &test_key(7);
+        let first = sample_block().encrypt(key, 0, 0).unwrap();
+        let second = sample_block().encrypt(key, 0, 0).unwrap();
+        let differing = first
+            .as_ref()
+            .as_ref()
+            .chunks(16)
+            .zip(second.as_ref().as_ref().chunks(16))
+            .filter(|(a, b)| a != b)
+            .count();
+        assert_eq!(differing, BLOCK_SIZE / 16);
+    }
+
+    #[test]
+    fn not_my_block() {
+        let key = &test_key(7);
+        let other = sample_block().encrypt(&test_key(8), 0, 0).unwrap();
+        assert!(matches!(
+            other.decrypt(key, 0, 0),
+            Err(crate::Error::Block(Error::NotMyBlock))
+        ));
+        let random = EncryptedBlock::from(RawBlock::new_rand(BLOCK_SIZE).unwrap());
+        assert!(matches!(
+            random.decrypt(key, 0, 0),
+            Err(crate::Error::Block(Error::NotMyBlock))
+        ));
+        let elsewhere = sample_block().encrypt(key, 0, 1).unwrap();
+        assert!(matches!(
+            elsewhere.decrypt(key, 0, 0),
+            Err(crate::Error::Block(Error::NotMyBlock))
+        ));
+    }
+
+    #[test]
+    fn tampering_detected() {
+        let key = &test_key(7);
+        let moved = sample_block().encrypt(key, 4, 0).unwrap();
+        assert!(matches!(
+            moved.decrypt(key, 5, 0),
+            Err(crate::Error::Block(Error::AuthenticationFailed))
+        ));
+
+        let mut bytes: Vec<u8> = sample_block().encrypt(key, 4, 0).unwrap().into_raw().into();
+        bytes[NONCE_SIZE + 100] ^= 1;
+        let flipped = EncryptedBlock::from(RawBlock::from(bytes));
+        assert!(matches!(
+            flipped.decrypt(key, 4, 0),
+            Err(crate::Error::Block(Error::AuthenticationFailed))
+        ));
+    }
+
+    #[test]
+    fn every_block_size() {
+        let key = &test_key(7);
+        for block_size in [MIN_BLOCK_SIZE, 4096, MAX_BLOCK_SIZE] {
+            let mut block = Block::new(block_size);
+            block.data_mut().fill(0x5a);
+            block.update_checksum();
+            let sealed = block.encrypt(key, 3, 1).unwrap();
+            assert_eq!(sealed.as_ref().len(), block_size);
+            let opened = sealed.decrypt(key, 3, 1).unwrap();
+            assert!(opened.validate_checksum());
+            assert_eq!(opened.data(), block.data());
+        }
+    }
+}
@@ -1,6 +1,48 @@
+//! Blocks in each of the states they pass through
+//!
+//! A [`RawBlock`] is whatever bytes a device holds at an index. Seed sectors
+//! and random fill are only ever handled raw. A raw block that is to be read
+//! as part of a chain becomes an [`EncryptedBlock`], which is authenticated
+//! and decrypted into a [`Block`]: the plaintext, made up of a header linking
+//! it into its chain and the data it carries. Going back, a `Block` is
+//! encrypted into a fresh `EncryptedBlock`, which is written as its raw bytes.
+//! Moving between `RawBlock` and `EncryptedBlock` never copies.
+//!
+//! Every block on an extent has the same size, a power of two between
+//! [`MIN_BLOCK_SIZE`] and [`MAX_BLOCK_SIZE`]. The size is not stored; it is
+//! implied by the random bytes at the start of the salt block, see
+//! [`implied_block_size`].
+
+use openssl::sha::Sha256;
+
+mod decrypted;
+mod encrypted;
 mod raw;
 
+pub use decrypted::{Block, BLOCK_HEADER_SIZE, END_OF_CHAIN};
+pub use encrypted::{EncryptedBlock, Error, BLOCK_OVERHEAD};
 pub use raw::RawBlock;
 
-
+/// Block size used unless another is asked for
 pub const BLOCK_SIZE: usize = 1024;
+
+pub const MIN_BLOCK_SIZE: usize = 512;
+pub const MAX_BLOCK_SIZE: usize = 64 * 1024;
+
+/// Whether blocks of `block_size` bytes can be used
+pub fn is_valid_block_size(block_size: usize) -> bool {
+    block_size.is_power_of_two() && (MIN_BLOCK_SIZE..=MAX_BLOCK_SIZE).contains(&block_size)
+}
+
+/// The block size implied by the first [`MIN_BLOCK_SIZE`] bytes of a device
+///
+/// The bytes are hashed, and the hash picks one of the valid block sizes.
+/// Random fill implies some block size just as well as a real salt block
+/// does, so nothing on disk gives away that the size was chosen.
+pub fn implied_block_size(prefix: &[u8]) -> usize {
+    let mut hasher = Sha256::new();
+    hasher.update(b"rubberhose block size");
+    hasher.update(&prefix[..MIN_BLOCK_SIZE]);
+    let n_sizes = (MAX_BLOCK_SIZE / MIN_BLOCK_SIZE).trailing_zeros() + 1;
+    MIN_BLOCK_SIZE << (hasher.finish()[0] as u32 % n_sizes)
+}
@@ -1,4 +1,119 @@
+use openssl::error::ErrorStack;
+use openssl::rand::rand_bytes;
+
+use std::fmt;
+
 use super::*;
+use crate::crc;
+
+/// A disk sector, the smallest unit that can be read or written from the block device
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RawBlock(Box<[u8]>);
+
+impl RawBlock {
+    pub fn new(block_size: usize) -> RawBlock {
+        RawBlock(vec![0; block_size].into_boxed_slice())
+    }
+
+    pub fn new_rand(block_size: usize) -> Result<RawBlock, ErrorStack> {
+        let mut s = RawBlock::new(block_size);
+        s.randomize()?;
+        Ok(s)
+    }
+
+    /// Random contents for the salt block, whose first bytes imply
+    /// `block_size` through [`implied_block_size`]
+    pub fn new_salt(block_size: usize) -> Result<RawBlock, ErrorStack> {
+        assert!(
+            is_valid_block_size(block_size),
+            "invalid block size {}",
+            block_size
+        );
+        let mut s = RawBlock::new(block_size);
+        loop {
+            s.randomize()?;
+            if implied_block_size(&s.0[..MIN_BLOCK_SIZE]) == block_size {
+                return Ok(s);
+            }
+        }
+    }
+
+    /// Randomize all data within self
+    pub fn randomize(&mut self) -> Result<(), ErrorStack> {
+        rand_bytes(&mut self.0)
+    }
+
+    /// Size of the block in bytes
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn crc(&self) -> u64 {
+        crc::crc64(&self.0)
+    }
+
+    /// Outputs a format line header for blocks of `block_size` bytes
+    pub fn header(f: &mut fmt::Formatter<'_>, block_size: usize) -> fmt::Result {
+        write!(
+            f,
+            "crc         0                          7   {:4x}                        {:4x}",
+            block_size - 8,
+            block_size - 1
+        )
+    }
+}
+
+impl From<Vec<u8>> for RawBlock {
+    fn from(bytes: Vec<u8>) -> RawBlock {
+        RawBlock(bytes.into_boxed_slice())
+    }
+}
+
+impl From<RawBlock> for Vec<u8> {
+    fn from(block: RawBlock) -> Vec<u8> {
+        block.0.into_vec()
+    }
+}
+
+impl fmt::Display for RawBlock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:016x} {:02x?} {:02x?}",
+            self.crc(),
+            &self.0[0..8],
+            &self.0[self.0.len() - 8..]
+        )
+    }
+}
+
+impl AsRef<[u8]> for RawBlock {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl AsMut<[u8]> for RawBlock {
+    fn as_mut(&mut self) -> &mut [u8] {
+        &mut self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-#[repr(transparent)]
-pub struct RawBlock([u8; BLOCK_SIZE]);
+    #[test]
+    fn salt_implies_block_size() {
+        for shift in 0..8 {
+            let block_size = MIN_BLOCK_SIZE << shift;
+            let salt = RawBlock::new_salt(block_size).unwrap();
+            assert_eq!(salt.len(), block_size);
+            assert_eq!(implied_block_size(salt.as_ref()), block_size);
+        }
+    }
+}
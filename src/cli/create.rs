@@ -0,0 +1,82 @@
+use rubberhose::block::{self, BLOCK_SIZE, MAX_BLOCK_SIZE, MIN_BLOCK_SIZE};
+use rubberhose::device::{BlockDevice, MmapImage, RawDevice};
+use rubberhose::Extent;
+
+use super::{is_block_device, parse_size, passphrase, Args, Error};
+
+pub const USAGE: &str =
+    "rubberhose create <image> --size <size> [--block-size <size>] [--aspects <n> | --stdin]
+rubberhose create <device> [--block-size <size>] [--aspects <n> | --stdin]
+
+    Create a new image of the given size (with an optional K, M, G or T
+    suffix), or take over a whole block device, erasing all it holds. Either
+    is filled with random data, then aspects are created on it. With
+    --aspects, that many passphrases are prompted for on the terminal; with
+    --stdin, one aspect is created per line of standard input. The block
+    size is a power of two from 512 bytes to 64K, 1K by default.";
+
+/// `rubberhose create`: build and randomise a new image, then create aspects
+///
+/// Nothing is printed on success, so the output gives away nothing about how
+/// many aspects, if any, were created.
+pub fn create(args: Args) -> Result<(), Error> {
+    args.only(&["size", "block-size", "aspects", "stdin"], 1)?;
+    let path = args.positional(0, "image")?;
+    let device = is_block_device(path);
+    let block_size = match args.option("block-size") {
+        Some(size) => match parse_size(size)? {
+            size if block::is_valid_block_size(size as usize) => size as usize,
+            _ => {
+                return Err(Error::Usage(format!(
+                    "block size must be a power of two from {} to {}",
+                    MIN_BLOCK_SIZE, MAX_BLOCK_SIZE
+                )))
+            }
+        },
+        None => BLOCK_SIZE,
+    };
+    let n_blocks = match (args.option("size"), device) {
+        (Some(_), true) => return Err(Error::Usage("--size can't be given for a device".into())),
+        (Some(size), false) => parse_size(size)? / block_size as u64,
+        (None, true) => u64::MAX,
+        (None, false) => return Err(Error::Usage("missing --size".into())),
+    };
+    if n_blocks < 2 {
+        return Err(Error::Usage(format!(
+            "an image needs at least {} bytes",
+            2 * block_size
+        )));
+    }
+
+    let passphrases = match (args.option("aspects"), args.flag("stdin")) {
+        (Some(_), true) => return Err(Error::Usage("--aspects and --stdin conflict".into())),
+        (Some(n), false) => {
+            let n: usize = n
+                .parse()
+                .map_err(|_| Error::Usage(format!("invalid aspect count {}", n)))?;
+            (0..n)
+                .map(|_| passphrase::prompt_new())
+                .collect::<Result<_, _>>()?
+        }
+        (None, true) => passphrase::read_lines()?,
+        (None, false) => Vec::new(),
+    };
+
+    let extent = if device {
+        let mut device = RawDevice::open(path, block_size)?;
+        if device.len()? < 2 {
+            return Err(Error::Usage(format!("{} is too small", path)));
+        }
+        device.randomize()?;
+        Extent::new(device)?.into_handle()
+    } else {
+        let mut image = MmapImage::create(path, n_blocks, block_size)?;
+        image.randomize()?;
+        Extent::new(image)?.into_handle()
+    };
+    for text in passphrases {
+        extent.create_aspect(extent.keyword(text)?)?;
+    }
+    extent.lock()?.flush()?;
+    Ok(())
+}
@@ -0,0 +1,18 @@
+use super::{stream, Args, Error};
+
+pub const USAGE: &str =
+    "rubberhose destroy <image> [--passphrase-file <path>] [--unlock <path>] [--guard <n>]
+
+    Destroy an aspect: its seed pointer and every one of its blocks are
+    overwritten with random data, leaving the image as if the aspect had
+    never been created. --unlock and --guard work as for put.";
+
+/// `rubberhose destroy`: erase the aspect a passphrase unlocks
+pub fn destroy(args: Args) -> Result<(), Error> {
+    args.only(&["passphrase-file", "unlock", "guard"], 1)?;
+    let aspect = stream::open(&args, false)?.into_inner()?;
+    let extent = aspect.extent().clone();
+    aspect.destroy()?;
+    extent.lock()?.flush()?;
+    Ok(())
+}
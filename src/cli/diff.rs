@@ -0,0 +1,38 @@
+use rubberhose::device::{self, ImageFile};
+use rubberhose::{snapshot, Extent};
+
+use super::{passphrase, Args, Error};
+
+pub const USAGE: &str = "rubberhose diff <before> <after> [--passphrase-file <path>]
+
+    Compare two snapshots of an image and report what an observer holding
+    both could learn: how many blocks changed, whether they cluster together
+    or after the seed index of the aspect that --passphrase-file unlocks, and
+    how random the changed blocks look.";
+
+/// `rubberhose diff`: measure how much two snapshots of an image give away
+pub fn diff(args: Args) -> Result<(), Error> {
+    args.only(&["passphrase-file"], 2)?;
+    let before = args.positional(0, "before")?;
+    let after = args.positional(1, "after")?;
+    let block_size = device::probe_block_size(after)?;
+    let seed_index = match args.option("passphrase-file") {
+        Some(file) => {
+            let extent = Extent::new(ImageFile::open_readonly(after, block_size)?)?.into_handle();
+            let keyword = extent.keyword(passphrase::read_file(file)?)?;
+            Some(
+                keyword
+                    .seed_index(extent.n_blocks())
+                    .map_err(rubberhose::Error::from)?,
+            )
+        }
+        None => None,
+    };
+    let report = snapshot::diff(
+        &mut ImageFile::open_readonly(before, block_size)?,
+        &mut ImageFile::open_readonly(after, block_size)?,
+        seed_index,
+    )?;
+    println!("{}", report);
+    Ok(())
+}
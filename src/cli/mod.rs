@@ -0,0 +1,223 @@
+use std::fmt;
+use std::io;
+use std::os::unix::fs::FileTypeExt;
+
+use rubberhose::{aspect, extent};
+
+mod create;
+mod destroy;
+mod diff;
+mod mount;
+mod nbd;
+mod passphrase;
+mod rekey;
+mod stream;
+
+pub use create::create;
+pub use destroy::destroy;
+pub use diff::diff;
+pub use mount::mount;
+pub use nbd::nbd;
+pub use rekey::rekey;
+pub use stream::{get, info, put};
+
+const EXIT_STATUS: &str = "exit status: 0 on success, 1 on I/O errors, 2 on usage errors, 3 when no
+aspect matches the passphrase, 4 when the aspect is damaged";
+
+/// Usage text for every subcommand
+pub fn usage() -> String {
+    let sections = [
+        create::USAGE,
+        stream::USAGE,
+        rekey::USAGE,
+        destroy::USAGE,
+        mount::USAGE,
+        nbd::USAGE,
+        diff::USAGE,
+        EXIT_STATUS,
+    ];
+    format!("usage:\n\n{}", sections.join("\n\n"))
+}
+
+/// Errors reported by the command line tool, each mapped to an exit code
+#[derive(Debug)]
+pub enum Error {
+    /// The command line could not be understood
+    Usage(String),
+    /// Reading or writing the image failed
+    Io(io::Error),
+    /// The image could not hold or yield an aspect
+    Aspect(aspect::Error),
+    /// Anything else the library reported, such as a cryptographic failure
+    Failed(rubberhose::Error),
+}
+
+impl Error {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::Io(_) | Error::Failed(_) | Error::Aspect(aspect::Error::ExtentFull) => 1,
+            Error::Usage(_) => 2,
+            // A wrong passphrase and an image without aspects look the same
+            Error::Aspect(aspect::Error::SeedNotFound) => 3,
+            Error::Aspect(_) => 4,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        // Aspect errors surfacing through `AspectCursor` keep their own codes
+        rubberhose::Error::from(e).into()
+    }
+}
+
+impl From<rubberhose::Error> for Error {
+    fn from(e: rubberhose::Error) -> Error {
+        match e {
+            rubberhose::Error::Io(e) => Error::Io(e),
+            rubberhose::Error::Aspect(e) => Error::Aspect(e),
+            e => Error::Failed(e),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Usage(message) => write!(f, "{}", message),
+            Error::Io(e) => write!(f, "I/O error: {}", e),
+            Error::Aspect(aspect::Error::SeedNotFound) => {
+                write!(f, "no aspect matches this passphrase")
+            }
+            Error::Aspect(aspect::Error::ExtentFull) => write!(f, "no space left on the image"),
+            Error::Aspect(e) => write!(f, "the aspect is damaged: {}", e),
+            Error::Failed(rubberhose::Error::Extent(extent::Error::Guarded)) => {
+                write!(f, "not every aspect has been unlocked, refusing to write")
+            }
+            Error::Failed(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+/// The arguments following a subcommand, split into positionals and
+/// `--name value` / `--flag` options
+pub struct Args {
+    positional: Vec<String>,
+    options: Vec<(String, Option<String>)>,
+}
+
+impl Args {
+    /// Split `args`, where `flags` lists the options that take no value
+    pub fn parse<I: IntoIterator<Item = String>>(args: I, flags: &[&str]) -> Result<Args, Error> {
+        let mut positional = Vec::new();
+        let mut options = Vec::new();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            match arg.strip_prefix("--") {
+                Some(name) if flags.contains(&name) => options.push((name.to_string(), None)),
+                Some(name) => {
+                    let value = args
+                        .next()
+                        .ok_or_else(|| Error::Usage(format!("--{} needs a value", name)))?;
+                    options.push((name.to_string(), Some(value)));
+                }
+                None => positional.push(arg),
+            }
+        }
+        Ok(Args {
+            positional,
+            options,
+        })
+    }
+
+    /// The positional argument at `index`, named `name` in error messages
+    pub fn positional(&self, index: usize, name: &str) -> Result<&str, Error> {
+        self.positional
+            .get(index)
+            .map(String::as_str)
+            .ok_or_else(|| Error::Usage(format!("missing <{}>", name)))
+    }
+
+    pub fn flag(&self, name: &str) -> bool {
+        self.options.iter().any(|(option, _)| option == name)
+    }
+
+    pub fn option(&self, name: &str) -> Option<&str> {
+        self.options
+            .iter()
+            .rev()
+            .find(|(option, _)| option == name)
+            .and_then(|(_, value)| value.as_deref())
+    }
+
+    /// Reject options the subcommand doesn't know about
+    pub fn only(&self, known: &[&str], max_positional: usize) -> Result<(), Error> {
+        if let Some((option, _)) = self
+            .options
+            .iter()
+            .find(|(o, _)| !known.contains(&o.as_str()))
+        {
+            return Err(Error::Usage(format!("unknown option --{}", option)));
+        }
+        if self.positional.len() > max_positional {
+            return Err(Error::Usage(format!(
+                "unexpected argument {}",
+                self.positional[max_positional]
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Whether a path names a block special file rather than an image file
+pub fn is_block_device(path: &str) -> bool {
+    std::fs::metadata(path).is_ok_and(|meta| meta.file_type().is_block_device())
+}
+
+/// Parse a size in bytes, with an optional K, M, G or T suffix
+pub fn parse_size(text: &str) -> Result<u64, Error> {
+    let error = || Error::Usage(format!("invalid size {}", text));
+    let (digits, shift) = match text.chars().last().map(|c| c.to_ascii_uppercase()) {
+        Some('K') => (&text[..text.len() - 1], 10),
+        Some('M') => (&text[..text.len() - 1], 20),
+        Some('G') => (&text[..text.len() - 1], 30),
+        Some('T') => (&text[..text.len() - 1], 40),
+        _ => (text, 0),
+    };
+    let value: u64 = digits.parse().map_err(|_| error())?;
+    value.checked_mul(1 << shift).ok_or_else(error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Args {
+        Args::parse(args.iter().map(|a| a.to_string()), &["stdin"]).unwrap()
+    }
+
+    #[test]
+    fn parse_args() {
+        let parsed = args(&["image.bin", "--size", "1M", "--stdin"]);
+        assert_eq!(parsed.positional(0, "image").unwrap(), "image.bin");
+        assert!(parsed.positional(1, "other").is_err());
+        assert_eq!(parsed.option("size"), Some("1M"));
+        assert!(parsed.flag("stdin"));
+        assert!(!parsed.flag("size-in-blocks"));
+        assert!(parsed.only(&["size", "stdin"], 1).is_ok());
+        assert!(parsed.only(&["size"], 1).is_err());
+        assert!(parsed.only(&["size", "stdin"], 0).is_err());
+        assert!(Args::parse(vec!["--size".to_string()], &[]).is_err());
+    }
+
+    #[test]
+    fn sizes() {
+        assert_eq!(parse_size("1024").unwrap(), 1024);
+        assert_eq!(parse_size("4k").unwrap(), 4096);
+        assert_eq!(parse_size("2M").unwrap(), 2 << 20);
+        assert_eq!(parse_size("1G").unwrap(), 1 << 30);
+        assert!(parse_size("").is_err());
+        assert!(parse_size("12Q").is_err());
+        assert!(parse_size("99999999999T").is_err());
+    }
+}
@@ -0,0 +1,39 @@
+use rubberhose::fs::{self, FileSystem};
+use rubberhose::fuse::{self, Session};
+
+use super::{stream, Args, Error};
+
+pub const USAGE: &str =
+    "rubberhose mount <image> <dir> [--passphrase-file <path>] [--unlock <path>] [--guard <n>]
+    [--chaff <n>]
+
+    Mount the filesystem kept in an aspect on <dir>, creating an empty one if
+    the aspect holds no data. Runs until the directory is unmounted with
+    umount, or until interrupted with SIGINT or SIGTERM, which also unmounts
+    it, then writes all changes back to the image; fsync also does so.
+    --unlock, --guard and --chaff work as for put.";
+
+impl From<fs::Error> for Error {
+    fn from(e: fs::Error) -> Error {
+        match e {
+            fs::Error::Io(e) => e.into(),
+            e => Error::Io(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("{:?}", e),
+            )),
+        }
+    }
+}
+
+/// `rubberhose mount`: serve the aspect's filesystem over FUSE
+pub fn mount(args: Args) -> Result<(), Error> {
+    args.only(&["passphrase-file", "unlock", "guard", "chaff"], 2)?;
+    let cursor = stream::open(&args, false)?;
+    let dir = args.positional(1, "dir")?;
+    let fs = FileSystem::open(cursor)?;
+    fuse::stop_on_signals()?;
+    let mut channel = fuse::mount(dir)?;
+    Session::new(fs).run(&mut channel)?;
+    fuse::unmount(dir)?;
+    Ok(())
+}
@@ -0,0 +1,58 @@
+use std::fs;
+use std::io::Write;
+use std::os::unix::net::UnixListener;
+
+use rubberhose::nbd::Server;
+
+use super::{parse_size, stream, Args, Error};
+
+pub const USAGE: &str =
+    "rubberhose nbd <image> <socket> [--size <size>] [--once] [--passphrase-file <path>]
+    [--unlock <path>] [--guard <n>] [--chaff <n>]
+
+    Serve an aspect as an NBD export on a Unix socket, for example to
+    nbd-client -unix. The export is as long as the aspect's data; --size
+    grows it first. Clients are served one at a time until interrupted, or
+    only the first one with --once. --unlock, --guard and --chaff work as
+    for put.";
+
+/// `rubberhose nbd`: export the aspect's stream as a block device
+pub fn nbd(args: Args) -> Result<(), Error> {
+    args.only(
+        &[
+            "passphrase-file",
+            "unlock",
+            "guard",
+            "chaff",
+            "size",
+            "once",
+        ],
+        2,
+    )?;
+    let socket = args.positional(1, "socket")?;
+    let size = args.option("size").map(parse_size).transpose()?;
+    let mut cursor = stream::open(&args, false)?;
+    if let Some(size) = size {
+        if size > cursor.len() {
+            cursor.set_len(size)?;
+            cursor.flush()?;
+        }
+    }
+
+    // Each client's writes are flushed when it leaves, see `Server::serve`
+    let listener = UnixListener::bind(socket)?;
+    let mut server = Server::new(cursor);
+    let result = (|| {
+        for stream in listener.incoming() {
+            if let Err(e) = server.serve(stream?) {
+                log::warn!("NBD client dropped: {}", e);
+            }
+            if args.flag("once") {
+                break;
+            }
+        }
+        Ok(())
+    })();
+    fs::remove_file(socket)?;
+    result
+}
@@ -0,0 +1,90 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+/// Restores the terminal's echo setting when dropped
+struct EchoGuard<'a> {
+    tty: &'a File,
+    original: libc::termios,
+}
+
+impl<'a> EchoGuard<'a> {
+    fn disable(tty: &'a File) -> io::Result<EchoGuard<'a>> {
+        let mut original = unsafe { std::mem::zeroed::<libc::termios>() };
+        if unsafe { libc::tcgetattr(tty.as_raw_fd(), &mut original) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut silent = original;
+        silent.c_lflag &= !libc::ECHO;
+        silent.c_lflag |= libc::ECHONL;
+        if unsafe { libc::tcsetattr(tty.as_raw_fd(), libc::TCSANOW, &silent) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(EchoGuard { tty, original })
+    }
+}
+
+impl Drop for EchoGuard<'_> {
+    fn drop(&mut self) {
+        unsafe { libc::tcsetattr(self.tty.as_raw_fd(), libc::TCSANOW, &self.original) };
+    }
+}
+
+fn strip_newline(mut line: String) -> String {
+    if line.ends_with('\n') {
+        line.pop();
+        if line.ends_with('\r') {
+            line.pop();
+        }
+    }
+    line
+}
+
+/// Prompt for a passphrase on the controlling terminal, without echoing it
+pub fn prompt(message: &str) -> io::Result<String> {
+    let tty = OpenOptions::new().read(true).write(true).open("/dev/tty")?;
+    (&tty).write_all(message.as_bytes())?;
+    (&tty).flush()?;
+    let _guard = EchoGuard::disable(&tty)?;
+    let mut line = String::new();
+    BufReader::new(&tty).read_line(&mut line)?;
+    Ok(strip_newline(line))
+}
+
+/// Prompt twice for a new passphrase, insisting both entries match
+pub fn prompt_new() -> io::Result<String> {
+    loop {
+        let first = prompt("New passphrase: ")?;
+        let second = prompt("Repeat passphrase: ")?;
+        if first == second {
+            return Ok(first);
+        }
+        eprintln!("Passphrases do not match, try again");
+    }
+}
+
+/// Passphrases one per line, ignoring empty lines
+fn lines<R: BufRead>(reader: R) -> io::Result<Vec<String>> {
+    reader
+        .lines()
+        .filter(|line| !matches!(line, Ok(line) if line.is_empty()))
+        .collect()
+}
+
+/// Read passphrases from stdin, one per line, ignoring empty lines
+pub fn read_lines() -> io::Result<Vec<String>> {
+    lines(io::stdin().lock())
+}
+
+/// Read passphrases from a file, one per line, ignoring empty lines
+pub fn read_file_lines<P: AsRef<Path>>(path: P) -> io::Result<Vec<String>> {
+    lines(BufReader::new(File::open(path)?))
+}
+
+/// Read a passphrase from the first line of a file
+pub fn read_file<P: AsRef<Path>>(path: P) -> io::Result<String> {
+    let mut line = String::new();
+    BufReader::new(File::open(path)?).read_line(&mut line)?;
+    Ok(strip_newline(line))
+}
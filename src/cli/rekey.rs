@@ -0,0 +1,28 @@
+use super::{passphrase, stream, Args, Error};
+
+pub const USAGE: &str =
+    "rubberhose rekey <image> [--passphrase-file <path>] [--new-passphrase-file <path>]
+    [--unlock <path>] [--guard <n>]
+
+    Move an aspect to a new passphrase, prompted for twice on the terminal
+    unless read from the first line of --new-passphrase-file. Afterwards the
+    old passphrase opens nothing, and the blocks it did are overwritten. The
+    image needs as much free space as the aspect takes up. --unlock and
+    --guard work as for put.";
+
+/// `rubberhose rekey`: change the passphrase of an aspect
+pub fn rekey(args: Args) -> Result<(), Error> {
+    args.only(
+        &["passphrase-file", "new-passphrase-file", "unlock", "guard"],
+        1,
+    )?;
+    let mut aspect = stream::open(&args, false)?.into_inner()?;
+    let text = match args.option("new-passphrase-file") {
+        Some(file) => passphrase::read_file(file)?,
+        None => passphrase::prompt_new()?,
+    };
+    let keyword = aspect.extent().keyword(text)?;
+    aspect.rekey(keyword)?;
+    aspect.extent().lock()?.flush()?;
+    Ok(())
+}
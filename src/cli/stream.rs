@@ -0,0 +1,128 @@
+use std::io::{self, Write};
+
+use rubberhose::device::{self, BlockDevice, ImageFile, MmapImage, RawDevice};
+use rubberhose::extent::Chaff;
+use rubberhose::{AspectCursor, Extent, ExtentHandle};
+
+use super::{is_block_device, passphrase, Args, Error};
+
+pub const USAGE: &str = "rubberhose put <image> [--passphrase-file <path>] [--unlock <path>]
+               [--guard <n>] [--chaff <n>] < data
+rubberhose get <image> [--passphrase-file <path>] > data
+rubberhose info <image> [--passphrase-file <path>]
+
+    Replace the contents of an aspect with standard input, write its contents
+    to standard output, or describe it. The passphrase is prompted for on the
+    terminal unless read from the first line of --passphrase-file.
+
+    Writing may overwrite any aspect that is not unlocked. --unlock also
+    opens the aspects whose passphrases are listed in a file, one per line,
+    so that their blocks are kept. --guard refuses to write unless <n>
+    aspects were unlocked in all. --chaff hides which blocks were written
+    among decoy writes: every flush also overwrites <n> free blocks with
+    random data and re-encrypts <n> blocks of the unlocked aspects.";
+
+/// Move an extent into a handle, guarded if asked to be
+fn into_handle<T: BlockDevice + Send + 'static>(
+    extent: Extent<T>,
+    guard: Option<usize>,
+    chaff: Chaff,
+) -> ExtentHandle {
+    let extent = extent.with_chaff(chaff);
+    match guard {
+        Some(n_aspects) => extent.with_guard(n_aspects).into_handle(),
+        None => extent.into_handle(),
+    }
+}
+
+/// Parse the value of a count option such as --guard
+fn count(args: &Args, name: &str) -> Result<Option<u64>, Error> {
+    match args.option(name) {
+        Some(n) => Ok(Some(n.parse().map_err(|_| {
+            Error::Usage(format!("invalid --{} count {}", name, n))
+        })?)),
+        None => Ok(None),
+    }
+}
+
+/// Open the image named on the command line and the aspect its passphrase
+/// unlocks, read-only if nothing is going to be written
+///
+/// The aspects named by --unlock are opened as well, only so that their
+/// blocks are known to be in use.
+pub fn open(args: &Args, read_only: bool) -> Result<AspectCursor, Error> {
+    let path = args.positional(0, "image")?;
+    let guard = count(args, "guard")?.map(|n| n as usize);
+    let n_chaff = count(args, "chaff")?.unwrap_or(0);
+    let chaff = Chaff {
+        free_blocks: n_chaff,
+        owned_blocks: n_chaff,
+    };
+    let unlock = match args.option("unlock") {
+        Some(file) => passphrase::read_file_lines(file)?,
+        None => Vec::new(),
+    };
+    let block_size = device::probe_block_size(path)?;
+    let extent = if is_block_device(path) && read_only {
+        Extent::new(RawDevice::open_readonly(path, block_size)?)?.into_handle()
+    } else if is_block_device(path) {
+        into_handle(
+            Extent::new(RawDevice::open(path, block_size)?)?,
+            guard,
+            chaff,
+        )
+    } else if read_only {
+        Extent::new(ImageFile::open_readonly(path, block_size)?)?.into_handle()
+    } else {
+        into_handle(
+            Extent::new(MmapImage::open(path, block_size)?)?,
+            guard,
+            chaff,
+        )
+    };
+    let text = match args.option("passphrase-file") {
+        Some(file) => passphrase::read_file(file)?,
+        None => passphrase::prompt("Passphrase: ")?,
+    };
+    let aspect = extent.open_aspect(extent.keyword(text)?)?;
+    for text in unlock {
+        extent.open_aspect(extent.keyword(text)?)?;
+    }
+    Ok(AspectCursor::new(aspect)?)
+}
+
+/// `rubberhose put`: replace the aspect's contents with standard input
+pub fn put(args: Args) -> Result<(), Error> {
+    args.only(&["passphrase-file", "unlock", "guard", "chaff"], 1)?;
+    let mut cursor = open(&args, false)?;
+    let len = io::copy(&mut io::stdin().lock(), &mut cursor)?;
+    cursor.set_len(len)?;
+    cursor.flush()?;
+    Ok(())
+}
+
+/// `rubberhose get`: write the aspect's contents to standard output
+pub fn get(args: Args) -> Result<(), Error> {
+    args.only(&["passphrase-file"], 1)?;
+    let mut cursor = open(&args, true)?;
+    let mut stdout = io::stdout().lock();
+    io::copy(&mut cursor, &mut stdout)?;
+    stdout.flush()?;
+    Ok(())
+}
+
+/// `rubberhose info`: describe the aspect
+///
+/// Only what the passphrase holder could work out by reading the aspect is
+/// shown; in particular nothing about other aspects or free space.
+pub fn info(args: Args) -> Result<(), Error> {
+    args.only(&["passphrase-file"], 1)?;
+    let cursor = open(&args, true)?;
+    let mut stdout = io::stdout().lock();
+    let aspect = cursor.get_ref();
+    writeln!(stdout, "block size:    {}", aspect.block_size())?;
+    writeln!(stdout, "image blocks:  {}", aspect.extent().n_blocks())?;
+    writeln!(stdout, "aspect blocks: {}", aspect.len())?;
+    writeln!(stdout, "length:        {}", cursor.len())?;
+    Ok(())
+}
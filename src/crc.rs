@@ -1,16 +1,15 @@
-
 /// Calculate the crc for some slice
 pub fn crc64<'a, I>(data: I) -> u64
 where
-    I: IntoIterator<Item=&'a u8>,
-    {
+    I: IntoIterator<Item = &'a u8>,
+{
     const TABLE: [u64; 256] = gen_crc_table();
     let mut crc: u64 = 0;
     for b in data {
         let t = (crc >> 56) as u8 ^ b;
         crc = TABLE[t as usize] ^ (crc << 8);
     }
-    return crc;
+    crc
 }
 
 /// Generates a lookup table for crc calculation
@@ -40,5 +39,5 @@ const fn gen_crc_table() -> [u64; 256] {
         i += 1;
     }
 
-    return table;
+    table
 }
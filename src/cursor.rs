@@ -0,0 +1,392 @@
+use std::cmp::min;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::mem::{size_of, ManuallyDrop};
+use std::ptr;
+
+use crate::aspect::{self, Aspect};
+
+/// Bytes at the start of the first block holding the length of the stream
+const LENGTH_SIZE: u64 = size_of::<u64>() as u64;
+
+fn too_large() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "offset past the largest possible stream",
+    )
+}
+
+/// A byte-addressable view of an aspect, usable like a file
+///
+/// The first 8 bytes of the seed block store the length of the stream, the
+/// remainder of the chain is the stream itself. Errors from the aspect are
+/// passed on wrapped in an `io::Error`, from which `crate::Error` recovers
+/// them.
+///
+/// Writes that extend the stream only note the new length, which reaches the
+/// seed block on `flush`, `into_inner` or drop, rather than rewriting it
+/// each time.
+pub struct AspectCursor {
+    aspect: Aspect,
+    position: u64,
+    len: u64,
+    /// Whether `len` is ahead of the length in the seed block
+    len_dirty: bool,
+}
+
+impl AspectCursor {
+    /// Wrap an aspect, reading the stream length from its seed block
+    pub fn new(mut aspect: Aspect) -> io::Result<AspectCursor> {
+        let seed = aspect.read_block(0)?;
+        let len = u64::from_be_bytes(seed.data()[..LENGTH_SIZE as usize].try_into().unwrap());
+        Ok(AspectCursor {
+            aspect,
+            position: 0,
+            len,
+            len_dirty: false,
+        })
+    }
+
+    /// Length of the stream in bytes
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    /// Set the length of the stream, growing the chain as needed. Blocks
+    /// past a shortened end are randomised and released.
+    pub fn set_len(&mut self, len: u64) -> io::Result<()> {
+        if len < self.len {
+            let (last_block, _) = self.locate(len.saturating_sub(1))?;
+            self.aspect.truncate(last_block + 1)?;
+        }
+        // Zero the cut-off bytes left in the last block so that everything
+        // past the end of the stream reads as zero again, as it would have
+        // after `push_block`
+        let kept = self.aspect.len() * self.aspect.data_size() as u64 - LENGTH_SIZE;
+        let mut offset = len;
+        while offset < min(self.len, kept) {
+            let (block_id, start) = self.locate(offset)?;
+            let end = min(
+                self.aspect.data_size() as u64,
+                start as u64 + self.len - offset,
+            ) as usize;
+            let mut block = self.aspect.read_block(block_id)?;
+            block.data_mut()[start..end].fill(0);
+            self.aspect.write_block(block_id, block)?;
+            offset += (end - start) as u64;
+        }
+        self.reserve(len)?;
+        self.store_len(len)
+    }
+
+    /// The underlying aspect
+    pub fn get_ref(&self) -> &Aspect {
+        &self.aspect
+    }
+
+    /// Store the length, then release the underlying aspect
+    pub fn into_inner(mut self) -> io::Result<Aspect> {
+        self.store_dirty_len()?;
+        let this = ManuallyDrop::new(self);
+        // SAFETY: `this` is never dropped or used again, so the aspect is
+        // moved out exactly once; the other fields own nothing
+        Ok(unsafe { ptr::read(&this.aspect) })
+    }
+
+    /// Map a stream offset onto a block id and an offset into its payload
+    fn locate(&self, offset: u64) -> io::Result<(u64, usize)> {
+        let offset = offset.checked_add(LENGTH_SIZE).ok_or_else(too_large)?;
+        let data_size = self.aspect.data_size() as u64;
+        Ok((offset / data_size, (offset % data_size) as usize))
+    }
+
+    /// Make sure the chain is long enough to hold `len` bytes. Should that
+    /// fail, the chain is cut back to its old length rather than left half
+    /// grown
+    fn reserve(&mut self, len: u64) -> io::Result<()> {
+        let (last_block, _) = self.locate(len.saturating_sub(1))?;
+        let old_len = self.aspect.len();
+        if last_block < old_len {
+            return Ok(());
+        }
+        if last_block + 1 - old_len > self.aspect.extent().lock()?.free_blocks() {
+            return Err(crate::Error::from(aspect::Error::ExtentFull).into());
+        }
+        while self.aspect.len() <= last_block {
+            if let Err(e) = self.aspect.push_block() {
+                self.aspect.truncate(old_len)?;
+                return Err(e.into());
+            }
+        }
+        Ok(())
+    }
+
+    fn store_len(&mut self, len: u64) -> io::Result<()> {
+        let mut seed = self.aspect.read_block(0)?;
+        seed.data_mut()[..LENGTH_SIZE as usize].copy_from_slice(&len.to_be_bytes());
+        self.aspect.write_block(0, seed)?;
+        self.len = len;
+        self.len_dirty = false;
+        Ok(())
+    }
+
+    fn store_dirty_len(&mut self) -> io::Result<()> {
+        match self.len_dirty {
+            true => self.store_len(self.len),
+            false => Ok(()),
+        }
+    }
+}
+
+impl Read for AspectCursor {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position >= self.len {
+            return Ok(0);
+        }
+        let (block_id, offset) = self.locate(self.position)?;
+        let remaining = (self.len - self.position) as usize;
+        let n = min(min(buf.len(), remaining), self.aspect.data_size() - offset);
+        let block = self.aspect.read_block(block_id)?;
+        buf[..n].copy_from_slice(&block.data()[offset..offset + n]);
+        self.position += n as u64;
+        Ok(n)
+    }
+}
+
+impl Write for AspectCursor {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let (block_id, offset) = self.locate(self.position)?;
+        let n = min(buf.len(), self.aspect.data_size() - offset);
+        let end = self.position.checked_add(n as u64).ok_or_else(too_large)?;
+        self.reserve(end)?;
+
+        // Blocks skipped over by a seek past the end were written zeroed by
+        // `push_block`, so only the block being written needs updating
+        let mut block = self.aspect.read_block(block_id)?;
+        block.data_mut()[offset..offset + n].copy_from_slice(&buf[..n]);
+        self.aspect.write_block(block_id, block)?;
+        self.position = end;
+        if end > self.len {
+            self.len = end;
+            self.len_dirty = true;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.store_dirty_len()?;
+        Ok(self.aspect.extent().lock()?.flush()?)
+    }
+}
+
+impl Drop for AspectCursor {
+    fn drop(&mut self) {
+        if let Err(e) = self.store_dirty_len() {
+            log::warn!("Stream length lost: {}", e);
+        }
+    }
+}
+
+impl Seek for AspectCursor {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.len.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+        match position {
+            Some(position) => {
+                self.position = position;
+                Ok(position)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::{BLOCK_SIZE, MIN_BLOCK_SIZE};
+    use crate::test_util::new_cursor;
+
+    #[test]
+    fn write_then_read() {
+        let mut cursor = new_cursor(32, BLOCK_SIZE);
+        let data: Vec<u8> = (0..5000u32).map(|i| (i % 251) as u8).collect();
+        cursor.write_all(&data).unwrap();
+        assert_eq!(cursor.len(), data.len() as u64);
+
+        cursor.seek(SeekFrom::Start(0)).unwrap();
+        let mut read = Vec::new();
+        cursor.read_to_end(&mut read).unwrap();
+        assert_eq!(read, data);
+    }
+
+    #[test]
+    fn small_blocks() {
+        let mut cursor = new_cursor(32, MIN_BLOCK_SIZE);
+        let data: Vec<u8> = (0..5000u32).map(|i| (i % 251) as u8).collect();
+        cursor.write_all(&data).unwrap();
+        let data_size = cursor.get_ref().data_size() as u64;
+        assert_eq!(
+            cursor.get_ref().len(),
+            (5000 + LENGTH_SIZE).div_ceil(data_size)
+        );
+
+        cursor.seek(SeekFrom::Start(0)).unwrap();
+        let mut read = Vec::new();
+        cursor.read_to_end(&mut read).unwrap();
+        assert_eq!(read, data);
+    }
+
+    #[test]
+    fn persists_across_reopen() {
+        let mut cursor = new_cursor(32, BLOCK_SIZE);
+        cursor.write_all(&[0xab; 3000]).unwrap();
+        let aspect = cursor.into_inner().unwrap();
+        let extent = aspect.extent().clone();
+        drop(aspect);
+
+        let aspect = extent
+            .open_aspect(extent.keyword("hello".to_string()).unwrap())
+            .unwrap();
+        let mut cursor = AspectCursor::new(aspect).unwrap();
+        let mut read = Vec::new();
+        cursor.read_to_end(&mut read).unwrap();
+        assert_eq!(read, vec![0xab; 3000]);
+    }
+
+    /// The length as stored in the seed block
+    fn stored_len(cursor: &mut AspectCursor) -> u64 {
+        let seed = cursor.aspect.read_block(0).unwrap();
+        u64::from_be_bytes(seed.data()[..LENGTH_SIZE as usize].try_into().unwrap())
+    }
+
+    #[test]
+    fn length_stored_lazily() {
+        let mut cursor = new_cursor(32, BLOCK_SIZE);
+        cursor.write_all(&[0xab; 3000]).unwrap();
+        assert_eq!(cursor.len(), 3000);
+        assert_eq!(stored_len(&mut cursor), 0);
+        cursor.flush().unwrap();
+        assert_eq!(stored_len(&mut cursor), 3000);
+
+        // Dropping the cursor stores it too
+        cursor.write_all(&[0xcd; 1000]).unwrap();
+        let extent = cursor.get_ref().extent().clone();
+        drop(cursor);
+        let aspect = extent
+            .open_aspect(extent.keyword("hello".to_string()).unwrap())
+            .unwrap();
+        assert_eq!(AspectCursor::new(aspect).unwrap().len(), 4000);
+    }
+
+    #[test]
+    fn seek_past_end() {
+        let mut cursor = new_cursor(32, BLOCK_SIZE);
+        cursor.write_all(b"start").unwrap();
+        cursor.seek(SeekFrom::Current(2500)).unwrap();
+        cursor.write_all(b"end").unwrap();
+        assert_eq!(cursor.len(), 2508);
+
+        let mut read = Vec::new();
+        cursor.seek(SeekFrom::End(-2508)).unwrap();
+        cursor.read_to_end(&mut read).unwrap();
+        assert_eq!(&read[..5], b"start");
+        assert!(read[5..2505].iter().all(|&b| b == 0));
+        assert_eq!(&read[2505..], b"end");
+    }
+
+    #[test]
+    fn overwrite_in_place() {
+        let mut cursor = new_cursor(32, BLOCK_SIZE);
+        cursor.write_all(&[1; 2000]).unwrap();
+        cursor.seek(SeekFrom::Start(1000)).unwrap();
+        cursor.write_all(&[2; 20]).unwrap();
+        assert_eq!(cursor.len(), 2000);
+
+        let mut read = vec![0; 30];
+        cursor.seek(SeekFrom::Start(995)).unwrap();
+        cursor.read_exact(&mut read).unwrap();
+        assert_eq!(&read[..5], &[1; 5]);
+        assert_eq!(&read[5..25], &[2; 20]);
+        assert_eq!(&read[25..], &[1; 5]);
+        assert!(cursor.seek(SeekFrom::Current(-10000)).is_err());
+    }
+
+    #[test]
+    fn set_len() {
+        let mut cursor = new_cursor(32, BLOCK_SIZE);
+        cursor.write_all(&[0xcd; 4000]).unwrap();
+        cursor.set_len(10).unwrap();
+        assert_eq!(cursor.len(), 10);
+
+        cursor.set_len(3000).unwrap();
+        cursor.seek(SeekFrom::Start(0)).unwrap();
+        let mut read = Vec::new();
+        cursor.read_to_end(&mut read).unwrap();
+        assert_eq!(&read[..10], &[0xcd; 10]);
+        assert!(read[10..].iter().all(|&b| b == 0));
+        assert_eq!(read.len(), 3000);
+    }
+
+    #[test]
+    fn far_seeks() {
+        let mut cursor = new_cursor(32, BLOCK_SIZE);
+        cursor.write_all(&[0xab; 1500]).unwrap();
+        let free = cursor.get_ref().extent().lock().unwrap().free_blocks();
+
+        cursor.seek(SeekFrom::Start(u64::MAX - 2)).unwrap();
+        let e = cursor.write(&[1]).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(cursor.read(&mut [0; 4]).unwrap(), 0);
+
+        // Past the end of the extent: nothing is left allocated
+        cursor.seek(SeekFrom::Start(1 << 30)).unwrap();
+        let e = crate::Error::from(cursor.write(&[1]).unwrap_err());
+        assert_eq!(e.aspect(), Some(aspect::Error::ExtentFull));
+        assert!(cursor.set_len(1 << 30).is_err());
+        assert_eq!(cursor.len(), 1500);
+        assert_eq!(cursor.get_ref().len(), 2);
+        assert_eq!(
+            cursor.get_ref().extent().lock().unwrap().free_blocks(),
+            free
+        );
+    }
+
+    #[test]
+    fn set_len_releases_blocks() {
+        let mut cursor = new_cursor(32, BLOCK_SIZE);
+        let free = cursor.get_ref().extent().lock().unwrap().free_blocks();
+        cursor.write_all(&[0xcd; 4000]).unwrap();
+        assert_eq!(cursor.get_ref().len(), 5);
+        cursor.set_len(1500).unwrap();
+        assert_eq!(cursor.get_ref().len(), 2);
+        cursor.set_len(0).unwrap();
+        assert_eq!(cursor.get_ref().len(), 1);
+        assert_eq!(
+            cursor.get_ref().extent().lock().unwrap().free_blocks(),
+            free
+        );
+
+        cursor.set_len(2000).unwrap();
+        cursor.seek(SeekFrom::Start(0)).unwrap();
+        let mut read = Vec::new();
+        cursor.read_to_end(&mut read).unwrap();
+        assert!(read.iter().all(|&b| b == 0));
+    }
+}
@@ -0,0 +1,993 @@
+use std::alloc::{self, Layout};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read, Seek, Write};
+use std::ops::{Deref, DerefMut};
+use std::os::unix::fs::{FileExt, FileTypeExt, OpenOptionsExt};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::ptr::NonNull;
+
+use memmap2::{MmapMut, MmapOptions};
+
+use crate::block::{self, BLOCK_SIZE, MIN_BLOCK_SIZE};
+use crate::error::Result;
+use crate::RawBlock;
+
+/// Describes a block device
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceMetadata {
+    pub name: &'static str,
+    /// The file backing the device, if any
+    pub path: Option<PathBuf>,
+    /// Number of blocks on the device
+    pub n_blocks: u64,
+    /// Size of each block in bytes
+    pub block_size: usize,
+    /// Whether writes are refused
+    pub read_only: bool,
+}
+
+/// A backing device, capable of reading and writing blocks
+///
+/// Failures of the underlying storage, as well as reads and writes past the
+/// end of the device, are reported as [`Error::Io`](crate::Error::Io).
+pub trait BlockDevice: fmt::Debug {
+    fn read(&mut self, index: u64) -> Result<RawBlock>;
+    fn write(&mut self, index: u64, block: &RawBlock) -> Result<()>;
+    fn len(&self) -> Result<u64>;
+
+    /// Size of the blocks read and written, the same for every block
+    fn block_size(&self) -> usize;
+
+    fn is_empty(&self) -> Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    fn meta(&self) -> Result<DeviceMetadata> {
+        Ok(DeviceMetadata {
+            name: "Not set",
+            path: None,
+            n_blocks: self.len()?,
+            block_size: self.block_size(),
+            read_only: false,
+        })
+    }
+
+    /// Make sure everything written so far has reached the backing storage
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Fill the entire disk with random information. The first block is
+    /// drawn so that it implies the device's block size, see
+    /// [`block::implied_block_size`]
+    /// WARNING: This erases all data
+    fn randomize(&mut self) -> Result<()> {
+        log::info!("Random-overwriting disk {self:?}");
+        let block_size = self.block_size();
+        for i in 0..self.len()? {
+            match i {
+                0 => self.write(i, &RawBlock::new_salt(block_size)?)?,
+                _ => self.write(i, &RawBlock::new_rand(block_size)?)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<T: BlockDevice + ?Sized> BlockDevice for Box<T> {
+    fn read(&mut self, index: u64) -> Result<RawBlock> {
+        (**self).read(index)
+    }
+
+    fn write(&mut self, index: u64, block: &RawBlock) -> Result<()> {
+        (**self).write(index, block)
+    }
+
+    fn len(&self) -> Result<u64> {
+        (**self).len()
+    }
+
+    fn block_size(&self) -> usize {
+        (**self).block_size()
+    }
+
+    fn meta(&self) -> Result<DeviceMetadata> {
+        (**self).meta()
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        (**self).flush()
+    }
+}
+
+/// Refuse to touch a block past the end of a device
+fn check_index(index: u64, n_blocks: u64) -> io::Result<()> {
+    match index < n_blocks {
+        true => Ok(()),
+        false => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "block 0x{:x} is past the end of the device ({} blocks)",
+                index, n_blocks
+            ),
+        )),
+    }
+}
+
+/// Refuse to write a block of the wrong size
+fn check_block(block: &RawBlock, block_size: usize) -> io::Result<()> {
+    match block.len() == block_size {
+        true => Ok(()),
+        false => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "{} byte block written to a device of {} byte blocks",
+                block.len(),
+                block_size
+            ),
+        )),
+    }
+}
+
+/// Refuse to open a device with an unusable block size
+fn check_block_size(block_size: usize) -> io::Result<()> {
+    match block::is_valid_block_size(block_size) {
+        true => Ok(()),
+        false => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid block size {}", block_size),
+        )),
+    }
+}
+
+/// The block size implied by the contents of an image or device, which
+/// must be opened with it
+pub fn probe_block_size<P: AsRef<Path>>(path: P) -> io::Result<usize> {
+    let mut prefix = [0; MIN_BLOCK_SIZE];
+    File::open(path)?.read_exact(&mut prefix)?;
+    Ok(block::implied_block_size(&prefix))
+}
+
+#[derive(Clone)]
+pub struct RAMDisk {
+    data: Vec<RawBlock>,
+    block_size: usize,
+}
+
+impl BlockDevice for RAMDisk {
+    fn meta(&self) -> Result<DeviceMetadata> {
+        Ok(DeviceMetadata {
+            name: "RAM disk",
+            path: None,
+            n_blocks: self.data.len() as u64,
+            block_size: self.block_size,
+            read_only: false,
+        })
+    }
+
+    fn read(&mut self, index: u64) -> Result<RawBlock> {
+        log::debug!("Read sector 0x{:x}", index);
+        check_index(index, self.data.len() as u64)?;
+        Ok(self.data[index as usize].clone())
+    }
+
+    fn write(&mut self, index: u64, block: &RawBlock) -> Result<()> {
+        log::debug!("Write sector 0x{:x}", index);
+        check_index(index, self.data.len() as u64)?;
+        check_block(block, self.block_size)?;
+        self.data[index as usize] = block.clone();
+        Ok(())
+    }
+
+    fn len(&self) -> Result<u64> {
+        Ok(self.data.len() as u64)
+    }
+
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+}
+
+impl fmt::Debug for RAMDisk {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let n_sectors = self.data.len();
+        writeln!(f, "Block Device: Size {:8x}", n_sectors)?;
+        for (i, sector) in self.data.iter().enumerate() {
+            writeln!(f, "{i:04x}: {sector}")?;
+        }
+        write!(f, " id    ")?;
+        RawBlock::header(f, self.block_size)?;
+        writeln!(f)?;
+        Ok(())
+    }
+}
+
+impl RAMDisk {
+    pub fn new(n_sectors: usize) -> RAMDisk {
+        RAMDisk::with_block_size(n_sectors, BLOCK_SIZE)
+    }
+
+    pub fn with_block_size(n_sectors: usize, block_size: usize) -> RAMDisk {
+        assert!(
+            block::is_valid_block_size(block_size),
+            "invalid block size {}",
+            block_size
+        );
+        log::info!(
+            "Created a RAMDisk with {} sectors of {} bytes",
+            n_sectors,
+            block_size
+        );
+        RAMDisk {
+            data: vec![RawBlock::new(block_size); n_sectors],
+            block_size,
+        }
+    }
+}
+
+/// An image file accessed with a seek and a read or write per block
+///
+/// Only whole blocks are used; any bytes past the last one are left alone.
+#[derive(Debug)]
+pub struct ImageFile {
+    path: PathBuf,
+    file: File,
+    n_blocks: u64,
+    block_size: usize,
+    read_only: bool,
+}
+
+impl BlockDevice for ImageFile {
+    fn meta(&self) -> Result<DeviceMetadata> {
+        Ok(DeviceMetadata {
+            name: "Image file",
+            path: Some(self.path.clone()),
+            n_blocks: self.n_blocks,
+            block_size: self.block_size,
+            read_only: self.read_only,
+        })
+    }
+
+    fn read(&mut self, index: u64) -> Result<RawBlock> {
+        log::debug!("Read sector 0x{:x}", index);
+        check_index(index, self.n_blocks)?;
+        self.file
+            .seek(io::SeekFrom::Start(index * self.block_size as u64))?;
+        let mut buffer = RawBlock::new(self.block_size);
+        self.file.read_exact(buffer.as_mut())?;
+        Ok(buffer)
+    }
+
+    fn write(&mut self, index: u64, block: &RawBlock) -> Result<()> {
+        log::debug!("Write sector 0x{:x}", index);
+        if self.read_only {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("{:?} is opened read-only", self.path),
+            )
+            .into());
+        }
+        check_index(index, self.n_blocks)?;
+        check_block(block, self.block_size)?;
+        self.file
+            .seek(io::SeekFrom::Start(index * self.block_size as u64))?;
+        self.file.write_all(block.as_ref())?;
+        Ok(())
+    }
+
+    fn len(&self) -> Result<u64> {
+        Ok(self.n_blocks)
+    }
+
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        if !self.read_only {
+            self.file.sync_data()?
+        }
+        Ok(())
+    }
+}
+
+impl ImageFile {
+    fn with_file(
+        path: &Path,
+        file: File,
+        block_size: usize,
+        read_only: bool,
+    ) -> io::Result<ImageFile> {
+        check_block_size(block_size)?;
+        let n_blocks = file.metadata()?.len() / block_size as u64;
+        Ok(ImageFile {
+            path: path.to_path_buf(),
+            file,
+            n_blocks,
+            block_size,
+            read_only,
+        })
+    }
+
+    /// Open an existing ImageFile from disk
+    pub fn open<P: AsRef<Path>>(filename: P, block_size: usize) -> io::Result<ImageFile> {
+        let file = File::options()
+            .read(true)
+            .write(true)
+            .open(filename.as_ref())?;
+        ImageFile::with_file(filename.as_ref(), file, block_size, false)
+    }
+
+    /// Open an existing ImageFile from disk, refusing any writes
+    pub fn open_readonly<P: AsRef<Path>>(filename: P, block_size: usize) -> io::Result<ImageFile> {
+        let file = File::open(filename.as_ref())?;
+        ImageFile::with_file(filename.as_ref(), file, block_size, true)
+    }
+
+    /// Create a new image file
+    pub fn create<P: AsRef<Path>>(
+        filename: P,
+        n_sectors: u64,
+        block_size: usize,
+    ) -> io::Result<ImageFile> {
+        check_block_size(block_size)?;
+        let mut file = File::options()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(filename.as_ref())?;
+        let sector = RawBlock::new(block_size);
+        for _ in 0..n_sectors {
+            file.write_all(sector.as_ref())?;
+        }
+        ImageFile::with_file(filename.as_ref(), file, block_size, false)
+    }
+}
+
+/// An image file accessed through a shared memory mapping
+///
+/// Blocks are copied straight in and out of the mapping, leaving it to the
+/// kernel to page the file in and write dirty pages back. [`MmapImage::flush`]
+/// forces them out with `msync`, as does dropping the image. Any bytes past the
+/// last whole block of the file are neither mapped nor touched.
+pub struct MmapImage {
+    path: PathBuf,
+    map: Option<MmapMut>,
+    block_size: usize,
+}
+
+impl fmt::Debug for MmapImage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MmapImage")
+            .field("path", &self.path)
+            .field("n_blocks", &self.n_blocks())
+            .finish()
+    }
+}
+
+impl BlockDevice for MmapImage {
+    fn meta(&self) -> Result<DeviceMetadata> {
+        Ok(DeviceMetadata {
+            name: "Memory-mapped image file",
+            path: Some(self.path.clone()),
+            n_blocks: self.n_blocks(),
+            block_size: self.block_size,
+            read_only: false,
+        })
+    }
+
+    fn read(&mut self, index: u64) -> Result<RawBlock> {
+        log::debug!("Read sector 0x{:x}", index);
+        check_index(index, self.n_blocks())?;
+        let range = self.range(index);
+        let mut block = RawBlock::new(self.block_size);
+        block
+            .as_mut()
+            .copy_from_slice(&self.map.as_ref().unwrap()[range]);
+        Ok(block)
+    }
+
+    fn write(&mut self, index: u64, block: &RawBlock) -> Result<()> {
+        log::debug!("Write sector 0x{:x}", index);
+        check_index(index, self.n_blocks())?;
+        check_block(block, self.block_size)?;
+        let range = self.range(index);
+        self.map.as_mut().unwrap()[range].copy_from_slice(block.as_ref());
+        Ok(())
+    }
+
+    fn len(&self) -> Result<u64> {
+        Ok(self.n_blocks())
+    }
+
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(MmapImage::flush(self)?)
+    }
+}
+
+impl MmapImage {
+    fn n_blocks(&self) -> u64 {
+        self.map
+            .as_ref()
+            .map_or(0, |map| (map.len() / self.block_size) as u64)
+    }
+
+    fn range(&self, index: u64) -> std::ops::Range<usize> {
+        let start = index as usize * self.block_size;
+        start..start + self.block_size
+    }
+
+    fn map(path: &Path, file: File, block_size: usize) -> io::Result<MmapImage> {
+        check_block_size(block_size)?;
+        let n_blocks = file.metadata()?.len() / block_size as u64;
+        let map_len = usize::try_from(n_blocks * block_size as u64)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "image too large to map"))?;
+        // Mapping nothing is an error, an image without blocks simply has no map
+        let map = match map_len {
+            0 => None,
+            _ => Some(unsafe { MmapOptions::new().len(map_len).map_mut(&file)? }),
+        };
+        Ok(MmapImage {
+            path: path.to_path_buf(),
+            map,
+            block_size,
+        })
+    }
+
+    /// Map an existing image file
+    pub fn open<P: AsRef<Path>>(filename: P, block_size: usize) -> io::Result<MmapImage> {
+        let file = File::options()
+            .read(true)
+            .write(true)
+            .open(filename.as_ref())?;
+        MmapImage::map(filename.as_ref(), file, block_size)
+    }
+
+    /// Create a new, sparse image file and map it
+    pub fn create<P: AsRef<Path>>(
+        filename: P,
+        n_sectors: u64,
+        block_size: usize,
+    ) -> io::Result<MmapImage> {
+        check_block_size(block_size)?;
+        let file = File::options()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(filename.as_ref())?;
+        file.set_len(n_sectors * block_size as u64)?;
+        MmapImage::map(filename.as_ref(), file, block_size)
+    }
+
+    /// Write dirty pages back to the file, waiting until they are on disk
+    pub fn flush(&self) -> io::Result<()> {
+        match &self.map {
+            Some(map) => map.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for MmapImage {
+    fn drop(&mut self) {
+        if let Err(e) = MmapImage::flush(self) {
+            log::error!("Failed to flush {:?}: {}", self.path, e);
+        }
+    }
+}
+
+/// `_IOR(0x12, 114, size_t)`, the size of a block device in bytes
+const BLKGETSIZE64: libc::c_ulong = 0x8008_1272;
+
+/// Logical block size assumed for anything that isn't a block device
+const DEFAULT_LOGICAL_BLOCK_SIZE: usize = 512;
+
+/// Memory alignment of transfer buffers, enough for any O_DIRECT device
+const BUFFER_ALIGN: usize = 4096;
+
+/// A zeroed heap buffer with the alignment O_DIRECT transfers need
+struct AlignedBuffer {
+    ptr: NonNull<u8>,
+    layout: Layout,
+}
+
+// The buffer is uniquely owned, like a `Box<[u8]>`
+unsafe impl Send for AlignedBuffer {}
+
+impl AlignedBuffer {
+    fn new(len: usize, align: usize) -> AlignedBuffer {
+        let layout = Layout::from_size_align(len, align).unwrap();
+        let ptr = NonNull::new(unsafe { alloc::alloc_zeroed(layout) })
+            .unwrap_or_else(|| alloc::handle_alloc_error(layout));
+        AlignedBuffer { ptr, layout }
+    }
+}
+
+impl Deref for AlignedBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr.as_ptr(), self.layout.size()) }
+    }
+}
+
+impl DerefMut for AlignedBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(self.ptr.as_ptr(), self.layout.size()) }
+    }
+}
+
+impl Drop for AlignedBuffer {
+    fn drop(&mut self) {
+        unsafe { alloc::dealloc(self.ptr.as_ptr(), self.layout) }
+    }
+}
+
+/// A block special file, such as a partition or a loop device
+///
+/// The device is opened with O_DIRECT, bypassing the page cache, where the
+/// underlying filesystem or driver allows it. Transfers are made in units of
+/// the larger of the block size and the device's logical block size; when the
+/// latter is larger, writing a block reads and rewrites the whole unit around
+/// it. Plain files are accepted too, assuming 512 byte logical blocks.
+pub struct RawDevice {
+    path: PathBuf,
+    file: File,
+    n_blocks: u64,
+    block_size: usize,
+    direct: bool,
+    /// Size of a single transfer, a multiple of both the block size and the
+    /// logical block size
+    io_size: usize,
+    buffer: AlignedBuffer,
+    read_only: bool,
+}
+
+impl fmt::Debug for RawDevice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RawDevice")
+            .field("path", &self.path)
+            .field("n_blocks", &self.n_blocks)
+            .field("block_size", &self.block_size)
+            .field("direct", &self.direct)
+            .field("io_size", &self.io_size)
+            .finish()
+    }
+}
+
+impl BlockDevice for RawDevice {
+    fn meta(&self) -> Result<DeviceMetadata> {
+        Ok(DeviceMetadata {
+            name: "Raw device",
+            path: Some(self.path.clone()),
+            n_blocks: self.n_blocks,
+            block_size: self.block_size,
+            read_only: self.read_only,
+        })
+    }
+
+    fn read(&mut self, index: u64) -> Result<RawBlock> {
+        log::debug!("Read sector 0x{:x}", index);
+        check_index(index, self.n_blocks)?;
+        let offset = self.read_unit(index)?;
+        let mut block = RawBlock::new(self.block_size);
+        block
+            .as_mut()
+            .copy_from_slice(&self.buffer[offset..offset + self.block_size]);
+        Ok(block)
+    }
+
+    fn write(&mut self, index: u64, block: &RawBlock) -> Result<()> {
+        log::debug!("Write sector 0x{:x}", index);
+        if self.read_only {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("{:?} is opened read-only", self.path),
+            )
+            .into());
+        }
+        check_index(index, self.n_blocks)?;
+        check_block(block, self.block_size)?;
+        let offset = match self.io_size == self.block_size {
+            true => 0,
+            false => self.read_unit(index)?,
+        };
+        self.buffer[offset..offset + self.block_size].copy_from_slice(block.as_ref());
+        self.file
+            .write_all_at(&self.buffer, self.unit_start(index))?;
+        Ok(())
+    }
+
+    fn len(&self) -> Result<u64> {
+        Ok(self.n_blocks)
+    }
+
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        if !self.read_only {
+            self.file.sync_data()?
+        }
+        Ok(())
+    }
+}
+
+impl RawDevice {
+    /// Open a block device or file for direct access
+    pub fn open<P: AsRef<Path>>(path: P, block_size: usize) -> io::Result<RawDevice> {
+        RawDevice::open_with(path.as_ref(), block_size, None, false)
+    }
+
+    /// Open a block device or file for direct access, refusing any writes
+    pub fn open_readonly<P: AsRef<Path>>(path: P, block_size: usize) -> io::Result<RawDevice> {
+        RawDevice::open_with(path.as_ref(), block_size, None, true)
+    }
+
+    fn open_with(
+        path: &Path,
+        block_size: usize,
+        logical_block_size: Option<usize>,
+        read_only: bool,
+    ) -> io::Result<RawDevice> {
+        check_block_size(block_size)?;
+        let mut options = File::options();
+        options.read(true).write(!read_only);
+        let (file, direct) = match options.clone().custom_flags(libc::O_DIRECT).open(path) {
+            Ok(file) => (file, true),
+            // Some filesystems, tmpfs among them, don't do direct I/O
+            Err(e) if e.raw_os_error() == Some(libc::EINVAL) => {
+                log::warn!("{:?} does not support O_DIRECT, using the page cache", path);
+                (options.open(path)?, false)
+            }
+            Err(e) => return Err(e),
+        };
+
+        let (size, device_block_size) = if file.metadata()?.file_type().is_block_device() {
+            let mut size: u64 = 0;
+            let mut block_size: libc::c_int = 0;
+            if unsafe { libc::ioctl(file.as_raw_fd(), BLKGETSIZE64, &mut size) } != 0
+                || unsafe { libc::ioctl(file.as_raw_fd(), libc::BLKSSZGET, &mut block_size) } != 0
+            {
+                return Err(io::Error::last_os_error());
+            }
+            (size, block_size as usize)
+        } else {
+            (file.metadata()?.len(), DEFAULT_LOGICAL_BLOCK_SIZE)
+        };
+        let logical_block_size = logical_block_size.unwrap_or(device_block_size);
+
+        let io_size = if block_size.is_multiple_of(logical_block_size) {
+            block_size
+        } else if logical_block_size.is_multiple_of(block_size) {
+            logical_block_size
+        } else {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!(
+                    "logical block size {} does not fit {} byte blocks",
+                    logical_block_size, block_size
+                ),
+            ));
+        };
+        // Only whole transfer units can be used
+        let n_blocks = size / io_size as u64 * (io_size / block_size) as u64;
+        log::info!(
+            "Opened {:?}: {} blocks, logical block size {}, direct I/O {}",
+            path,
+            n_blocks,
+            logical_block_size,
+            direct
+        );
+        Ok(RawDevice {
+            path: path.to_path_buf(),
+            file,
+            n_blocks,
+            block_size,
+            direct,
+            io_size,
+            buffer: AlignedBuffer::new(io_size, BUFFER_ALIGN.max(logical_block_size)),
+            read_only,
+        })
+    }
+
+    /// Whether transfers bypass the page cache
+    pub fn is_direct(&self) -> bool {
+        self.direct
+    }
+
+    /// Byte offset of the transfer unit holding a block
+    fn unit_start(&self, index: u64) -> u64 {
+        index * self.block_size as u64 / self.io_size as u64 * self.io_size as u64
+    }
+
+    /// Read the unit holding a block into the buffer, returning the block's
+    /// offset within it
+    fn read_unit(&mut self, index: u64) -> io::Result<usize> {
+        let start = self.unit_start(index);
+        self.file.read_exact_at(&mut self.buffer, start)?;
+        Ok((index * self.block_size as u64 - start) as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn read_and_write() {
+        let mut disk = RAMDisk::new(10);
+        let sector = RawBlock::new_rand(BLOCK_SIZE).unwrap();
+        disk.write(0, &sector).unwrap();
+        assert_eq!(disk.read(0).unwrap(), sector);
+    }
+
+    /// A unique path under the temporary directory, removed when dropped
+    struct TempFile(PathBuf);
+
+    impl std::ops::Deref for TempFile {
+        type Target = Path;
+
+        fn deref(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl AsRef<Path> for TempFile {
+        fn as_ref(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    fn temp_path(name: &str) -> TempFile {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let n = NEXT.fetch_add(1, Ordering::Relaxed);
+        TempFile(std::env::temp_dir().join(format!(
+            "rubberhose-{}-{}-{}.bin",
+            name,
+            std::process::id(),
+            n
+        )))
+    }
+
+    #[test]
+    fn persist_file() {
+        let path = temp_path("persist");
+        let sector = RawBlock::new_rand(BLOCK_SIZE).unwrap();
+        {
+            let mut disk = ImageFile::create(&path, 10, BLOCK_SIZE).unwrap();
+            disk.write(0, &sector).unwrap();
+            disk.write(9, &sector).unwrap();
+        }
+        {
+            let mut disk = ImageFile::open(&path, BLOCK_SIZE).unwrap();
+            assert_eq!(disk.len().unwrap(), 10);
+            assert_eq!(disk.read(0).unwrap(), sector);
+            assert_eq!(disk.read(1).unwrap(), RawBlock::new(BLOCK_SIZE));
+            assert_eq!(disk.read(9).unwrap(), sector);
+        }
+    }
+
+    #[test]
+    fn image_metadata() {
+        let path = temp_path("meta");
+        ImageFile::create(&path, 3, BLOCK_SIZE).unwrap();
+        // A trailing partial block is not part of the device
+        File::options()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(&[1; 10])
+            .unwrap();
+
+        let disk = ImageFile::open(&path, BLOCK_SIZE).unwrap();
+        let meta = disk.meta().unwrap();
+        assert_eq!(meta.path.as_deref(), Some(&*path));
+        assert_eq!(meta.n_blocks, 3);
+        assert!(!meta.read_only);
+
+        let mut disk = ImageFile::open_readonly(&path, BLOCK_SIZE).unwrap();
+        assert!(disk.meta().unwrap().read_only);
+        assert_eq!(disk.read(2).unwrap(), RawBlock::new(BLOCK_SIZE));
+        assert_eq!(RAMDisk::new(4).meta().unwrap().n_blocks, 4);
+    }
+
+    #[test]
+    fn readonly_refuses_writes() {
+        let path = temp_path("readonly");
+        ImageFile::create(&path, 1, BLOCK_SIZE).unwrap();
+        let mut disk = ImageFile::open_readonly(&path, BLOCK_SIZE).unwrap();
+        match disk.write(0, &RawBlock::new(BLOCK_SIZE)) {
+            Err(crate::Error::Io(e)) => assert_eq!(e.kind(), io::ErrorKind::PermissionDenied),
+            r => panic!("read-only write gave {:?}", r),
+        }
+    }
+
+    #[test]
+    fn out_of_range() {
+        let mut disk = RAMDisk::new(2);
+        assert!(matches!(disk.read(2), Err(crate::Error::Io(_))));
+        assert!(disk.write(2, &RawBlock::new(BLOCK_SIZE)).is_err());
+
+        let path = temp_path("range");
+        let mut disk = ImageFile::create(&path, 2, BLOCK_SIZE).unwrap();
+        assert!(disk.read(1).is_ok());
+        assert!(disk.read(2).is_err());
+    }
+
+    #[test]
+    fn mmap_persists() {
+        let path = temp_path("mmap");
+        let sector = RawBlock::new_rand(BLOCK_SIZE).unwrap();
+        {
+            let mut disk = MmapImage::create(&path, 10, BLOCK_SIZE).unwrap();
+            assert_eq!(disk.len().unwrap(), 10);
+            disk.write(9, &sector).unwrap();
+            BlockDevice::flush(&mut disk).unwrap();
+        }
+        {
+            // A trailing partial block is left alone
+            let file = File::options().append(true).open(&path).unwrap();
+            (&file).write_all(&[7; 100]).unwrap();
+            let mut disk = MmapImage::open(&path, BLOCK_SIZE).unwrap();
+            assert_eq!(disk.len().unwrap(), 10);
+            assert_eq!(disk.read(9).unwrap(), sector);
+            assert_eq!(disk.read(0).unwrap(), RawBlock::new(BLOCK_SIZE));
+            disk.write(0, &sector).unwrap();
+        }
+        let contents = fs::read(&path).unwrap();
+        assert_eq!(contents.len(), 10 * BLOCK_SIZE + 100);
+        assert_eq!(&contents[..BLOCK_SIZE], sector.as_ref());
+        assert_eq!(&contents[10 * BLOCK_SIZE..], &[7; 100]);
+
+        let empty = temp_path("mmap-empty");
+        assert!(MmapImage::create(&empty, 0, BLOCK_SIZE)
+            .unwrap()
+            .is_empty()
+            .unwrap());
+    }
+
+    fn raw_device_file(name: &str, n_bytes: usize) -> TempFile {
+        // Without O_DIRECT support in the temporary directory, RawDevice falls
+        // back to the page cache
+        let path = temp_path(name);
+        fs::write(&path, vec![0; n_bytes]).unwrap();
+        path
+    }
+
+    #[test]
+    fn raw_device() {
+        let path = raw_device_file("raw", 10 * BLOCK_SIZE + 700);
+        let sectors: Vec<RawBlock> = (0..10)
+            .map(|_| RawBlock::new_rand(BLOCK_SIZE).unwrap())
+            .collect();
+        {
+            let mut disk = RawDevice::open(&path, BLOCK_SIZE).unwrap();
+            assert_eq!(disk.len().unwrap(), 10);
+            for (i, sector) in sectors.iter().enumerate() {
+                disk.write(i as u64, sector).unwrap();
+            }
+            disk.flush().unwrap();
+        }
+        let mut disk = RawDevice::open(&path, BLOCK_SIZE).unwrap();
+        for (i, sector) in sectors.iter().enumerate() {
+            assert_eq!(&disk.read(i as u64).unwrap(), sector);
+        }
+        assert_eq!(
+            &fs::read(&path).unwrap()[BLOCK_SIZE..2 * BLOCK_SIZE],
+            sectors[1].as_ref()
+        );
+    }
+
+    #[test]
+    fn raw_device_readonly() {
+        let path = raw_device_file("raw-ro", 4 * BLOCK_SIZE);
+        let sector = RawBlock::new_rand(BLOCK_SIZE).unwrap();
+        RawDevice::open(&path, BLOCK_SIZE)
+            .unwrap()
+            .write(2, &sector)
+            .unwrap();
+        let mut disk = RawDevice::open_readonly(&path, BLOCK_SIZE).unwrap();
+        assert!(disk.meta().unwrap().read_only);
+        assert_eq!(disk.read(2).unwrap(), sector);
+        match disk.write(2, &RawBlock::new(BLOCK_SIZE)) {
+            Err(crate::Error::Io(e)) => assert_eq!(e.kind(), io::ErrorKind::PermissionDenied),
+            r => panic!("read-only write gave {:?}", r),
+        }
+        disk.flush().unwrap();
+        assert_eq!(
+            &fs::read(&path).unwrap()[2 * BLOCK_SIZE..3 * BLOCK_SIZE],
+            sector.as_ref()
+        );
+    }
+
+    #[test]
+    fn raw_device_large_logical_blocks() {
+        // With 4 KiB logical blocks, a trailing partial unit is unusable and
+        // writes must leave neighbouring blocks in the unit intact
+        let path = raw_device_file("raw-4k", 9 * BLOCK_SIZE);
+        let mut disk =
+            RawDevice::open_with(&path, BLOCK_SIZE, Some(4 * BLOCK_SIZE), false).unwrap();
+        assert_eq!(disk.len().unwrap(), 8);
+        let a = RawBlock::new_rand(BLOCK_SIZE).unwrap();
+        let b = RawBlock::new_rand(BLOCK_SIZE).unwrap();
+        disk.write(5, &a).unwrap();
+        disk.write(6, &b).unwrap();
+        assert_eq!(disk.read(5).unwrap(), a);
+        assert_eq!(disk.read(6).unwrap(), b);
+        assert_eq!(disk.read(4).unwrap(), RawBlock::new(BLOCK_SIZE));
+        assert!(RawDevice::open_with(&path, BLOCK_SIZE, Some(3000), false).is_err());
+    }
+
+    #[test]
+    fn block_sizes() {
+        let mut disk = RAMDisk::with_block_size(4, 4096);
+        assert_eq!(disk.meta().unwrap().block_size, 4096);
+        assert!(disk
+            .write(1, &RawBlock::new_rand(BLOCK_SIZE).unwrap())
+            .is_err());
+        disk.randomize().unwrap();
+        assert_eq!(disk.read(3).unwrap().len(), 4096);
+
+        let path = temp_path("block-size");
+        let mut disk = MmapImage::create(&path, 4, 4096).unwrap();
+        disk.randomize().unwrap();
+        BlockDevice::flush(&mut disk).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().len(), 4 * 4096);
+        assert_eq!(probe_block_size(&path).unwrap(), 4096);
+        assert_eq!(ImageFile::open(&path, 4096).unwrap().len().unwrap(), 4);
+        assert!(ImageFile::open(&path, 1000).is_err());
+    }
+
+    #[test]
+    #[ignore = "needs root and losetup to attach a loop device"]
+    fn raw_loop_device() {
+        use std::process::Command;
+
+        let path = raw_device_file("loop", 64 * BLOCK_SIZE);
+        let output = Command::new("losetup")
+            .args(["--find", "--show"])
+            .arg(&*path)
+            .output()
+            .unwrap();
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        let loop_device = String::from_utf8(output.stdout).unwrap().trim().to_string();
+
+        let sector = RawBlock::new_rand(BLOCK_SIZE).unwrap();
+        {
+            let mut disk = RawDevice::open(&loop_device, BLOCK_SIZE).unwrap();
+            assert_eq!(disk.len().unwrap(), 64);
+            disk.write(63, &sector).unwrap();
+            disk.flush().unwrap();
+            assert_eq!(disk.read(63).unwrap(), sector);
+        }
+        Command::new("losetup")
+            .arg("--detach")
+            .arg(&loop_device)
+            .status()
+            .unwrap();
+        assert_eq!(
+            &fs::read(&path).unwrap()[63 * BLOCK_SIZE..],
+            sector.as_ref()
+        );
+    }
+}
@@ -0,0 +1,121 @@
+use std::fmt;
+use std::io;
+use std::sync::PoisonError;
+
+use openssl::error::ErrorStack;
+
+use crate::{aspect, block, extent};
+
+/// Any error the crate can produce, from the device up to the aspect
+#[derive(Debug)]
+pub enum Error {
+    /// The backing device failed
+    Io(io::Error),
+    /// OpenSSL failed to derive a key, encrypt or decrypt
+    Crypto(ErrorStack),
+    /// A block could not be authenticated
+    Block(block::Error),
+    /// The extent could not be used
+    Extent(extent::Error),
+    /// An aspect could not be found, read or extended
+    Aspect(aspect::Error),
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+impl Error {
+    /// The aspect error this is, if any
+    pub fn aspect(&self) -> Option<aspect::Error> {
+        match self {
+            Error::Aspect(e) => Some(*e),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "I/O error: {}", e),
+            Error::Crypto(e) => write!(f, "cryptographic failure: {}", e),
+            Error::Block(e) => write!(f, "bad block: {:?}", e),
+            Error::Extent(e) => write!(f, "extent unusable: {:?}", e),
+            Error::Aspect(e) => write!(f, "aspect error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            Error::Crypto(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    /// Unwraps errors that were wrapped on their way through `io::Read` or
+    /// `io::Write` implementations
+    fn from(e: io::Error) -> Error {
+        if e.get_ref().is_some_and(|inner| inner.is::<Error>()) {
+            return *e.into_inner().unwrap().downcast::<Error>().unwrap();
+        }
+        Error::Io(e)
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(e: Error) -> io::Error {
+        match e {
+            Error::Io(e) => e,
+            e => io::Error::new(io::ErrorKind::InvalidData, e),
+        }
+    }
+}
+
+impl From<ErrorStack> for Error {
+    fn from(e: ErrorStack) -> Error {
+        Error::Crypto(e)
+    }
+}
+
+impl From<block::Error> for Error {
+    fn from(e: block::Error) -> Error {
+        Error::Block(e)
+    }
+}
+
+impl From<extent::Error> for Error {
+    fn from(e: extent::Error) -> Error {
+        Error::Extent(e)
+    }
+}
+
+impl<T> From<PoisonError<T>> for Error {
+    fn from(_e: PoisonError<T>) -> Error {
+        Error::Extent(extent::Error::PoisonedData)
+    }
+}
+
+impl From<aspect::Error> for Error {
+    fn from(e: aspect::Error) -> Error {
+        Error::Aspect(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_through_io() {
+        let e: io::Error = Error::Aspect(aspect::Error::ExtentFull).into();
+        assert_eq!(Error::from(e).aspect(), Some(aspect::Error::ExtentFull));
+
+        let e: io::Error = Error::Io(io::Error::other("device gone")).into();
+        assert!(e.get_ref().is_some_and(|inner| !inner.is::<Error>()));
+        assert!(matches!(Error::from(e), Error::Io(_)));
+    }
+}
@@ -0,0 +1,590 @@
+use crate::allocator::{Allocator, Linear, Random};
+use crate::device::BlockDevice;
+use crate::error::Result;
+use crate::keyword::{KdfParams, Salt};
+use crate::seed;
+use crate::{Aspect, EncryptedBlock, Keyword, RawBlock};
+use bitvec::prelude::*;
+use openssl::rand::rand_bytes;
+use openssl::sha::Sha256;
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// The device behind an extent handle, so handles needn't be generic
+pub type Device = Box<dyn BlockDevice + Send>;
+
+/// Block whose random fill is used as the key derivation salt. It is never
+/// handed out by the allocator
+pub const SALT_BLOCK: u64 = 0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// A thread panicked while holding the extent
+    PoisonedData,
+    /// The extent is guarded and not every aspect on it has been unlocked,
+    /// so a write could land on a block of one that hasn't
+    Guarded,
+}
+
+/// Decoy writes added to every flush, so that comparing snapshots of the
+/// disk doesn't tell which blocks an aspect really wrote
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Chaff {
+    /// Free blocks to overwrite with fresh random data
+    pub free_blocks: u64,
+    /// Blocks of open aspects to re-encrypt under fresh nonces
+    pub owned_blocks: u64,
+}
+
+/// A uniformly random number below `n`, which must not be zero
+fn random_below(n: u64) -> Result<u64> {
+    let mut bytes = [0; 16];
+    rand_bytes(&mut bytes)?;
+    Ok((u128::from_be_bytes(bytes) % n as u128) as u64)
+}
+
+#[derive(Clone)]
+pub struct ExtentHandle {
+    handle: Arc<Mutex<Extent<Device>>>,
+    n_blocks: u64,
+    block_size: usize,
+    salt: Salt,
+    kdf_params: KdfParams,
+}
+
+impl fmt::Debug for ExtentHandle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.handle.lock() {
+            Ok(extent) => extent.fmt(f),
+            Err(_) => write!(f, "Poisoned extent"),
+        }
+    }
+}
+
+impl ExtentHandle {
+    /// Number of blocks in the entire aspect
+    pub fn n_blocks(&self) -> u64 {
+        self.n_blocks
+    }
+
+    /// Size of every block on the extent
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    /// Derive the keyword for a passphrase on this extent
+    pub fn keyword(&self, text: String) -> Result<Keyword> {
+        Ok(Keyword::new(text, &self.salt, self.kdf_params)?)
+    }
+
+    /// Create a new aspect on disk
+    pub fn create_aspect(&self, keyword: Keyword) -> Result<Aspect> {
+        let mut aspect = Aspect::new(self, keyword);
+        aspect.write_seed_block()?;
+        Ok(aspect)
+    }
+
+    /// Open an existing aspect from disk. Its seed slot and blocks are marked
+    /// in use, so that no other aspect is allocated over them
+    pub fn open_aspect(&self, keyword: Keyword) -> Result<Aspect> {
+        Aspect::read_aspect(self, keyword)
+    }
+
+    // Return a handle to the underlying extent object
+    pub fn lock(&self) -> Result<MutexGuard<'_, Extent<Device>>> {
+        Ok(self.handle.lock()?)
+    }
+}
+
+/// A device divided into blocks, and the allocation state of this session
+///
+/// Nothing on disk records which blocks are in use, as that would give away
+/// how many aspects there are. Instead the usage map is rebuilt as aspects
+/// are opened or created: each one marks its seed slot and every block of
+/// its chain. Blocks of aspects that have not been unlocked this session
+/// look free, and may be handed out and overwritten. That is the price of
+/// deniability; to be safe, open every aspect before writing to any of them.
+///
+/// A guarded extent enforces this, given the number of aspects on it: every
+/// write is refused with [`Error::Guarded`] until that many have been
+/// unlocked.
+///
+/// Which free block an aspect grows into is up to the extent's
+/// [`Allocator`], [`Random`] unless told otherwise.
+pub struct Extent<T: BlockDevice + fmt::Debug> {
+    block_usage_map: BitVec,
+    /// Number of clear bits in `block_usage_map`
+    free_blocks: u64,
+    allocator: Box<dyn Allocator>,
+    /// Seed sectors known this session, and a map of their occupied slots
+    seed_sectors: HashMap<u64, BitVec>,
+    /// Keyword and chain position of every block of an open aspect, so that
+    /// chaff can re-encrypt them
+    owners: HashMap<u64, (Arc<Keyword>, u64)>,
+    /// Number of aspects to unlock before anything may be written
+    guard: Option<usize>,
+    chaff: Chaff,
+    device: T,
+    n_blocks: u64,
+    salt: Salt,
+    kdf_params: KdfParams,
+}
+
+impl<T: BlockDevice + fmt::Debug> fmt::Debug for Extent<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Extent:")?;
+        writeln!(f, "    Block Usage map: {}", self.block_usage_map)?;
+        writeln!(f, "    Allocator: {:?}", self.allocator)?;
+        writeln!(f, "    {:?}", self.device)?;
+        Ok(())
+    }
+}
+
+impl<T: BlockDevice + Send + 'static> Extent<T> {
+    /// Move self into a handle
+    pub fn into_handle(self) -> ExtentHandle {
+        let extent: Extent<Device> = Extent {
+            block_usage_map: self.block_usage_map,
+            free_blocks: self.free_blocks,
+            allocator: self.allocator,
+            seed_sectors: self.seed_sectors,
+            owners: self.owners,
+            guard: self.guard,
+            chaff: self.chaff,
+            device: Box::new(self.device),
+            n_blocks: self.n_blocks,
+            salt: self.salt,
+            kdf_params: self.kdf_params,
+        };
+        ExtentHandle {
+            n_blocks: extent.n_blocks(),
+            block_size: extent.device.block_size(),
+            salt: extent.salt,
+            kdf_params: extent.kdf_params,
+            handle: Arc::new(Mutex::new(extent)),
+        }
+    }
+}
+
+impl<T: BlockDevice + fmt::Debug> Extent<T> {
+    pub fn new(mut device: T) -> Result<Extent<T>> {
+        let n_blocks = device.len()?;
+        // The salt block and at least one block for an aspect
+        if n_blocks < 2 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "device too small").into());
+        }
+        let mut block_usage_map = bitvec![0; n_blocks as usize];
+        block_usage_map.set(SALT_BLOCK as usize, true);
+        Ok(Extent {
+            block_usage_map,
+            free_blocks: n_blocks - 1,
+            allocator: Box::new(Random),
+            seed_sectors: HashMap::new(),
+            owners: HashMap::new(),
+            guard: None,
+            chaff: Chaff::default(),
+            salt: Extent::derive_salt(&mut device, n_blocks)?,
+            device,
+            n_blocks,
+            kdf_params: KdfParams::default(),
+        })
+    }
+
+    /// Use non-default key derivation costs. Every user of the extent must
+    /// agree on these, as they are not stored on disk
+    pub fn with_kdf_params(mut self, kdf_params: KdfParams) -> Extent<T> {
+        self.kdf_params = kdf_params;
+        self
+    }
+
+    /// Choose free blocks with `allocator` rather than at random
+    pub fn with_allocator<A: Allocator + 'static>(mut self, allocator: A) -> Extent<T> {
+        self.allocator = Box::new(allocator);
+        self
+    }
+
+    /// Add decoy writes to every flush
+    pub fn with_chaff(mut self, chaff: Chaff) -> Extent<T> {
+        self.chaff = chaff;
+        self
+    }
+
+    /// Refuse writes until `n_aspects` aspects have been opened or created.
+    /// Creating an aspect is itself a write, so a new aspect can only be
+    /// added once all existing ones are unlocked
+    pub fn with_guard(mut self, n_aspects: usize) -> Extent<T> {
+        self.guard = Some(n_aspects);
+        self
+    }
+
+    /// Derive the salt from the device's geometry and the random contents of
+    /// the salt block, so that no recognisable salt needs to be stored
+    fn derive_salt(device: &mut T, n_blocks: u64) -> Result<Salt> {
+        let mut hasher = Sha256::new();
+        hasher.update(b"rubberhose salt");
+        hasher.update(&(device.block_size() as u64).to_be_bytes());
+        hasher.update(&n_blocks.to_be_bytes());
+        hasher.update(device.read(SALT_BLOCK)?.as_ref());
+        Ok(hasher.finish())
+    }
+
+    /// The underlying device
+    pub fn device(&self) -> &T {
+        &self.device
+    }
+
+    pub fn salt(&self) -> &Salt {
+        &self.salt
+    }
+
+    pub fn n_blocks(&self) -> u64 {
+        self.n_blocks
+    }
+
+    pub fn read_block(&mut self, block_index: u64) -> Result<EncryptedBlock> {
+        Ok(self.device.read(block_index)?.into())
+    }
+
+    pub fn write_block(&mut self, block_index: u64, block: &EncryptedBlock) -> Result<()> {
+        self.check_guard()?;
+        self.device.write(block_index, block.as_ref())
+    }
+
+    /// Read a block without treating it as encrypted, for seed sectors
+    pub fn read_raw(&mut self, block_index: u64) -> Result<RawBlock> {
+        self.device.read(block_index)
+    }
+
+    pub fn write_raw(&mut self, block_index: u64, block: &RawBlock) -> Result<()> {
+        self.check_guard()?;
+        self.device.write(block_index, block)
+    }
+
+    /// Number of aspects opened or created on the extent this session
+    pub fn unlocked_aspects(&self) -> usize {
+        self.seed_sectors
+            .values()
+            .map(|slots| slots.count_ones())
+            .sum()
+    }
+
+    /// Expect one aspect fewer on a guarded extent, after one was destroyed
+    pub fn forget_aspect(&mut self) {
+        if let Some(n_aspects) = &mut self.guard {
+            *n_aspects = n_aspects.saturating_sub(1);
+        }
+    }
+
+    /// Fail with [`Error::Guarded`] if the extent is guarded and not every
+    /// aspect on it has been unlocked
+    pub fn check_guard(&self) -> Result<()> {
+        match self.guard {
+            Some(n_aspects) if self.unlocked_aspects() < n_aspects => Err(Error::Guarded.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Make sure everything written has reached the device's storage, after
+    /// adding any chaff
+    pub fn flush(&mut self) -> Result<()> {
+        self.scatter_chaff()?;
+        self.device.flush()
+    }
+
+    /// Overwrite free blocks with random data and re-encrypt blocks of open
+    /// aspects under fresh nonces, as many as the chaff settings ask for.
+    /// Nothing is done while a guard holds, as free blocks may still belong
+    /// to aspects that aren't unlocked
+    pub fn scatter_chaff(&mut self) -> Result<()> {
+        if self.check_guard().is_err() {
+            log::debug!("Extent is guarded, adding no chaff");
+            return Ok(());
+        }
+        for index in self.pick_free_blocks(self.chaff.free_blocks)? {
+            let block = RawBlock::new_rand(self.device.block_size())?;
+            self.device.write(index, &block)?;
+        }
+
+        // Sample owned blocks without replacement
+        let mut owned: Vec<u64> = self.owners.keys().copied().collect();
+        let n = owned.len().min(self.chaff.owned_blocks as usize);
+        for i in 0..n {
+            let pick = i + random_below((owned.len() - i) as u64)? as usize;
+            owned.swap(i, pick);
+            let index = owned[i];
+            let (keyword, position) = &self.owners[&index];
+            let key = keyword.block_key(*position)?;
+            let position = *position;
+            // A block that no longer decrypts is left for its aspect to report
+            match self.read_block(index)?.decrypt(&key, index, position) {
+                Ok(block) => self
+                    .device
+                    .write(index, block.encrypt(&key, index, position)?.as_ref())?,
+                Err(crate::Error::Block(e)) => {
+                    log::warn!("Not re-encrypting block 0x{:x}: {:?}", index, e)
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Up to `n` distinct free blocks, drawn at random. Gives up early on a
+    /// nearly full extent rather than scanning it
+    fn pick_free_blocks(&self, n: u64) -> Result<Vec<u64>> {
+        let mut picked = Vec::new();
+        for _ in 0..n.saturating_mul(8) {
+            if picked.len() as u64 == n {
+                break;
+            }
+            let index = random_below(self.n_blocks)?;
+            if !self.is_allocated(index) && !picked.contains(&index) {
+                picked.push(index);
+            }
+        }
+        Ok(picked)
+    }
+
+    // Allocator functions
+    /// Mark the given block as deallocated
+    pub fn deallocate_block(&mut self, block_index: u64) {
+        self.owners.remove(&block_index);
+        if self.block_usage_map.replace(block_index as usize, false) {
+            self.free_blocks += 1;
+        }
+    }
+
+    /// Mark a block as block `position` of the aspect with `keyword`
+    pub fn claim_block(&mut self, block_index: u64, keyword: &Arc<Keyword>, position: u64) {
+        self.alloc_block(block_index);
+        self.owners.insert(block_index, (keyword.clone(), position));
+    }
+
+    /// Mark a block as owned
+    pub fn alloc_block(&mut self, block_index: u64) {
+        if !self.block_usage_map.replace(block_index as usize, true) {
+            self.free_blocks -= 1;
+        }
+    }
+
+    /// True if the block has been allocated to an open aspect
+    pub fn is_allocated(&self, block_index: u64) -> bool {
+        self.block_usage_map[block_index as usize]
+    }
+
+    /// Mark the first free block at or after `block_index`, wrapping around
+    /// the end of the extent. Returns `None` if every block is in use
+    pub fn alloc_next_block(&mut self, block_index: u64) -> Result<Option<u64>> {
+        let choice = Linear.choose(&self.block_usage_map, &[0; 32], block_index, 0)?;
+        if let Some(index) = choice {
+            self.alloc_block(index);
+        }
+        Ok(choice)
+    }
+
+    /// Claim the block the allocator chooses for block `block_id` of the
+    /// aspect with `keyword`, to be pointed at from `previous`. Returns
+    /// `None` if every block is in use
+    pub fn alloc_block_for(
+        &mut self,
+        keyword: &Arc<Keyword>,
+        previous: u64,
+        block_id: u64,
+    ) -> Result<Option<u64>> {
+        let choice = self.allocator.choose(
+            &self.block_usage_map,
+            &keyword.alloc_key()?,
+            previous,
+            block_id,
+        )?;
+        let Some(index) = choice else {
+            return Ok(None);
+        };
+        debug_assert!(
+            !self.is_allocated(index),
+            "{:?} chose a block in use",
+            self.allocator
+        );
+        self.claim_block(index, keyword, block_id);
+        Ok(Some(index))
+    }
+
+    /// Reserve a seed slot in the first sector at or after `block_index` that
+    /// can hold one: either a known seed sector with a free candidate slot,
+    /// or a free sector, which becomes a seed sector
+    pub fn alloc_seed_slot(
+        &mut self,
+        block_index: u64,
+        candidates: &[usize],
+    ) -> Option<(u64, usize)> {
+        let n_blocks = self.n_blocks;
+        for offset in 0..n_blocks {
+            let sector = (block_index + offset) % n_blocks;
+            let occupied = match self.seed_sectors.get(&sector) {
+                Some(occupied) => occupied.as_bitslice(),
+                None if !self.is_allocated(sector) => BitSlice::empty(),
+                None => continue,
+            };
+            if let Some(&slot) = candidates
+                .iter()
+                .find(|&&slot| !occupied.get(slot).is_some_and(|bit| *bit))
+            {
+                self.claim_seed_slot(sector, slot);
+                return Some((sector, slot));
+            }
+        }
+        None
+    }
+
+    /// Mark a slot of a seed sector as occupied
+    pub fn claim_seed_slot(&mut self, sector: u64, slot: usize) {
+        self.alloc_block(sector);
+        let n_slots = seed::n_slots(self.device.block_size());
+        self.seed_sectors
+            .entry(sector)
+            .or_insert_with(|| bitvec![0; n_slots])
+            .set(slot, true);
+    }
+
+    /// Mark a slot of a seed sector as free, releasing the sector once no
+    /// known slots remain
+    pub fn release_seed_slot(&mut self, sector: u64, slot: usize) {
+        if let Some(occupied) = self.seed_sectors.get_mut(&sector) {
+            occupied.set(slot, false);
+            if occupied.not_any() {
+                self.seed_sectors.remove(&sector);
+                self.deallocate_block(sector);
+            }
+        }
+    }
+
+    /// Number of blocks not allocated to any open aspect
+    pub fn free_blocks(&self) -> u64 {
+        self.free_blocks
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::BLOCK_SIZE;
+    use crate::test_util::random_disk;
+    use crate::RAMDisk;
+
+    #[test]
+    fn salt_follows_salt_block() {
+        let first = Extent::new(random_disk(4, BLOCK_SIZE)).unwrap();
+        let second = Extent::new(random_disk(4, BLOCK_SIZE)).unwrap();
+        assert_ne!(first.salt(), second.salt());
+    }
+
+    #[test]
+    fn too_small() {
+        for n_blocks in [0, 1] {
+            let e = Extent::new(RAMDisk::new(n_blocks)).unwrap_err();
+            assert!(matches!(e, crate::Error::Io(e) if e.kind() == io::ErrorKind::InvalidInput));
+        }
+        assert!(Extent::new(RAMDisk::new(2)).is_ok());
+    }
+
+    #[test]
+    fn salt_block_is_reserved() {
+        let mut extent = Extent::new(RAMDisk::new(4)).unwrap();
+        assert!(extent.is_allocated(SALT_BLOCK));
+        assert_eq!(extent.alloc_next_block(SALT_BLOCK).unwrap(), Some(1));
+    }
+
+    #[test]
+    fn alloc_until_full() {
+        let mut extent = Extent::new(RAMDisk::new(4)).unwrap();
+        assert_eq!(extent.free_blocks(), 3);
+        assert_eq!(extent.alloc_next_block(2).unwrap(), Some(2));
+        assert_eq!(extent.alloc_next_block(2).unwrap(), Some(3));
+        assert_eq!(extent.alloc_next_block(2).unwrap(), Some(1));
+        assert_eq!(extent.alloc_next_block(2).unwrap(), None);
+        assert_eq!(extent.free_blocks(), 0);
+    }
+
+    #[test]
+    fn free_count_follows_map() {
+        let mut extent = Extent::new(RAMDisk::new(8)).unwrap();
+        let check = |extent: &Extent<RAMDisk>| {
+            assert_eq!(
+                extent.free_blocks(),
+                extent.block_usage_map.count_zeros() as u64
+            );
+        };
+        // Marking a block twice, or freeing one twice, only counts once
+        extent.alloc_block(3);
+        extent.alloc_block(3);
+        check(&extent);
+        extent.deallocate_block(3);
+        extent.deallocate_block(3);
+        check(&extent);
+        extent.claim_seed_slot(5, 0);
+        extent.claim_seed_slot(5, 1);
+        check(&extent);
+        extent.release_seed_slot(5, 0);
+        check(&extent);
+        extent.release_seed_slot(5, 1);
+        check(&extent);
+        assert_eq!(extent.free_blocks(), 7);
+    }
+
+    /// Indices of the blocks that differ between two copies of a disk
+    fn changed_blocks(before: &mut RAMDisk, after: &mut RAMDisk) -> Vec<u64> {
+        (0..before.len().unwrap())
+            .filter(|&i| before.read(i).unwrap() != after.read(i).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn chaff_rewrites_free_blocks() {
+        let disk = random_disk(64, BLOCK_SIZE);
+        let mut before = disk.clone();
+        let chaff = Chaff {
+            free_blocks: 8,
+            owned_blocks: 8,
+        };
+        let mut extent = Extent::new(disk).unwrap().with_chaff(chaff);
+        extent.alloc_block(5);
+        extent.flush().unwrap();
+        let changed = changed_blocks(&mut before, &mut extent.device);
+        assert_eq!(changed.len(), 8);
+        assert!(!changed.contains(&SALT_BLOCK) && !changed.contains(&5));
+    }
+
+    #[test]
+    fn no_chaff_while_guarded() {
+        let disk = random_disk(16, BLOCK_SIZE);
+        let mut before = disk.clone();
+        let chaff = Chaff {
+            free_blocks: 8,
+            owned_blocks: 0,
+        };
+        let mut extent = Extent::new(disk).unwrap().with_chaff(chaff).with_guard(1);
+        extent.flush().unwrap();
+        assert!(changed_blocks(&mut before, &mut extent.device).is_empty());
+        extent.claim_seed_slot(3, 0);
+        extent.flush().unwrap();
+        assert_eq!(changed_blocks(&mut before, &mut extent.device).len(), 8);
+    }
+
+    #[test]
+    fn guard_counts_seed_slots() {
+        let mut extent = Extent::new(RAMDisk::new(4)).unwrap().with_guard(2);
+        let block = RawBlock::new(BLOCK_SIZE);
+        assert!(matches!(
+            extent.write_raw(1, &block),
+            Err(crate::Error::Extent(Error::Guarded))
+        ));
+        extent.claim_seed_slot(1, 0);
+        extent.claim_seed_slot(1, 5);
+        assert_eq!(extent.unlocked_aspects(), 2);
+        extent.write_raw(1, &block).unwrap();
+        extent.release_seed_slot(1, 5);
+        assert!(extent.check_guard().is_err());
+    }
+}
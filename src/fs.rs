@@ -0,0 +1,716 @@
+use std::collections::BTreeMap;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::AspectCursor;
+
+/// Identifies the start of a filesystem image within an aspect
+const MAGIC: &[u8; 4] = b"RHFS";
+
+const VERSION: u32 = 1;
+
+/// Inode number of the root directory
+pub const ROOT: u64 = 1;
+
+/// Longest name a directory entry may have
+pub const MAX_NAME: usize = 255;
+
+#[derive(Debug)]
+pub enum Error {
+    /// No such file or directory
+    NotFound,
+    /// The name is already taken
+    Exists,
+    /// A directory was expected
+    NotDirectory,
+    /// A file was expected
+    IsDirectory,
+    /// The directory still has entries
+    NotEmpty,
+    /// The name is empty, too long or otherwise unusable
+    InvalidName,
+    /// The stored filesystem could not be decoded
+    Corrupt,
+    /// There is no room left on the extent for the file to grow
+    NoSpace,
+    /// The file would be larger than can be addressed
+    TooLarge,
+    /// Reading or writing the aspect failed
+    Io(io::Error),
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    File,
+    Directory,
+}
+
+/// What `stat` needs to know about an inode
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Attr {
+    pub ino: u64,
+    pub kind: Kind,
+    pub size: u64,
+    /// Permission bits
+    pub mode: u16,
+    pub nlink: u32,
+    pub mtime: SystemTime,
+    /// Preferred I/O size, the payload of one block of the aspect
+    pub blksize: u32,
+}
+
+enum Node {
+    File(Vec<u8>),
+    Directory(BTreeMap<String, u64>),
+}
+
+struct Inode {
+    node: Node,
+    mode: u16,
+    mtime: SystemTime,
+    /// Containing directory, not stored but rebuilt when loading
+    parent: u64,
+}
+
+impl Inode {
+    fn new(node: Node, mode: u16, parent: u64) -> Inode {
+        Inode {
+            node,
+            mode,
+            mtime: SystemTime::now(),
+            parent,
+        }
+    }
+}
+
+/// A directory tree kept in the byte stream of an aspect
+///
+/// The whole tree is loaded into memory when opened. Changes stay there until
+/// [`FileSystem::flush`] writes the tree back out through the cursor, which
+/// re-encrypts every block it touches.
+///
+/// The stream holds a header (magic, version, next inode number, inode count)
+/// followed by every inode, all big-endian: number, mode, modification time,
+/// then either the contents of a file or the entries of a directory.
+pub struct FileSystem {
+    cursor: AspectCursor,
+    inodes: BTreeMap<u64, Inode>,
+    next_ino: u64,
+    dirty: bool,
+}
+
+impl FileSystem {
+    /// Load the filesystem stored in an aspect, creating an empty one if the
+    /// aspect holds no data yet
+    pub fn open(mut cursor: AspectCursor) -> Result<FileSystem, Error> {
+        if cursor.is_empty() {
+            let mut inodes = BTreeMap::new();
+            inodes.insert(
+                ROOT,
+                Inode::new(Node::Directory(BTreeMap::new()), 0o755, ROOT),
+            );
+            return Ok(FileSystem {
+                cursor,
+                inodes,
+                next_ino: ROOT + 1,
+                dirty: true,
+            });
+        }
+
+        let mut data = Vec::new();
+        cursor.seek(SeekFrom::Start(0))?;
+        cursor.read_to_end(&mut data)?;
+        let (inodes, next_ino) = decode(&data).ok_or(Error::Corrupt)?;
+        Ok(FileSystem {
+            cursor,
+            inodes,
+            next_ino,
+            dirty: false,
+        })
+    }
+
+    /// Whether there are changes not yet written to the aspect
+    pub fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    /// Write the tree back to the aspect if it has changed
+    pub fn flush(&mut self) -> Result<(), Error> {
+        if !self.dirty {
+            return Ok(());
+        }
+        let data = encode(&self.inodes, self.next_ino);
+        self.cursor.seek(SeekFrom::Start(0))?;
+        self.cursor.write_all(&data)?;
+        self.cursor.set_len(data.len() as u64)?;
+        self.cursor.flush()?;
+        self.dirty = false;
+        Ok(())
+    }
+
+    /// Release the underlying cursor, discarding unflushed changes
+    pub fn into_inner(self) -> AspectCursor {
+        self.cursor
+    }
+
+    /// The cursor the filesystem is stored in
+    pub fn cursor(&self) -> &AspectCursor {
+        &self.cursor
+    }
+
+    fn inode(&self, ino: u64) -> Result<&Inode, Error> {
+        self.inodes.get(&ino).ok_or(Error::NotFound)
+    }
+
+    fn inode_mut(&mut self, ino: u64) -> Result<&mut Inode, Error> {
+        self.inodes.get_mut(&ino).ok_or(Error::NotFound)
+    }
+
+    fn entries(&self, ino: u64) -> Result<&BTreeMap<String, u64>, Error> {
+        match &self.inode(ino)?.node {
+            Node::Directory(entries) => Ok(entries),
+            Node::File(_) => Err(Error::NotDirectory),
+        }
+    }
+
+    fn entries_mut(&mut self, ino: u64) -> Result<&mut BTreeMap<String, u64>, Error> {
+        match &mut self.inode_mut(ino)?.node {
+            Node::Directory(entries) => Ok(entries),
+            Node::File(_) => Err(Error::NotDirectory),
+        }
+    }
+
+    fn contents_mut(&mut self, ino: u64) -> Result<&mut Vec<u8>, Error> {
+        self.dirty = true;
+        let inode = self.inode_mut(ino)?;
+        inode.mtime = SystemTime::now();
+        match &mut inode.node {
+            Node::File(contents) => Ok(contents),
+            Node::Directory(_) => Err(Error::IsDirectory),
+        }
+    }
+
+    pub fn attr(&self, ino: u64) -> Result<Attr, Error> {
+        let inode = self.inode(ino)?;
+        let (kind, size, nlink) = match &inode.node {
+            Node::File(contents) => (Kind::File, contents.len() as u64, 1),
+            Node::Directory(entries) => {
+                let subdirs = entries
+                    .values()
+                    .filter(|ino| matches!(self.inodes[ino].node, Node::Directory(_)))
+                    .count();
+                (Kind::Directory, entries.len() as u64, 2 + subdirs as u32)
+            }
+        };
+        Ok(Attr {
+            ino,
+            kind,
+            size,
+            mode: inode.mode,
+            nlink,
+            mtime: inode.mtime,
+            blksize: self.cursor.get_ref().data_size() as u32,
+        })
+    }
+
+    /// Look up a name within a directory
+    pub fn lookup(&self, parent: u64, name: &str) -> Result<Attr, Error> {
+        let ino = *self.entries(parent)?.get(name).ok_or(Error::NotFound)?;
+        self.attr(ino)
+    }
+
+    /// The directory containing `ino`, the root being its own parent
+    pub fn parent(&self, ino: u64) -> Result<u64, Error> {
+        Ok(self.inode(ino)?.parent)
+    }
+
+    /// The entries of a directory, in name order
+    pub fn read_dir(&self, ino: u64) -> Result<Vec<(String, Attr)>, Error> {
+        self.entries(ino)?
+            .iter()
+            .map(|(name, &ino)| Ok((name.clone(), self.attr(ino)?)))
+            .collect()
+    }
+
+    /// Read up to `size` bytes of a file starting at `offset`
+    pub fn read(&self, ino: u64, offset: u64, size: usize) -> Result<&[u8], Error> {
+        match &self.inode(ino)?.node {
+            Node::File(contents) => {
+                let start = offset.min(contents.len() as u64) as usize;
+                let end = start.saturating_add(size).min(contents.len());
+                Ok(&contents[start..end])
+            }
+            Node::Directory(_) => Err(Error::IsDirectory),
+        }
+    }
+
+    /// Check that a file may be `len` bytes long. Growing it must fit in the
+    /// stream as stored now together with the free space of the extent, so
+    /// that sizes from the kernel can't exhaust memory before a flush
+    fn check_len(&self, ino: u64, len: u64) -> Result<usize, Error> {
+        let current = match &self.inode(ino)?.node {
+            Node::File(contents) => contents.len() as u64,
+            Node::Directory(_) => return Err(Error::IsDirectory),
+        };
+        if len > current {
+            let aspect = self.cursor.get_ref();
+            let free = aspect
+                .extent()
+                .lock()
+                .map_err(io::Error::from)?
+                .free_blocks();
+            let capacity = free
+                .saturating_mul(aspect.data_size() as u64)
+                .saturating_add(self.cursor.len());
+            if len > capacity {
+                return Err(Error::NoSpace);
+            }
+        }
+        usize::try_from(len).map_err(|_| Error::TooLarge)
+    }
+
+    /// Write to a file at `offset`, zero-filling any gap past its end
+    pub fn write(&mut self, ino: u64, offset: u64, data: &[u8]) -> Result<(), Error> {
+        let end = offset
+            .checked_add(data.len() as u64)
+            .ok_or(Error::TooLarge)?;
+        let end = self.check_len(ino, end)?;
+        let contents = self.contents_mut(ino)?;
+        let start = end - data.len();
+        if contents.len() < end {
+            contents.resize(end, 0);
+        }
+        contents[start..end].copy_from_slice(data);
+        Ok(())
+    }
+
+    pub fn set_len(&mut self, ino: u64, len: u64) -> Result<(), Error> {
+        let len = self.check_len(ino, len)?;
+        self.contents_mut(ino)?.resize(len, 0);
+        Ok(())
+    }
+
+    pub fn set_mode(&mut self, ino: u64, mode: u16) -> Result<(), Error> {
+        self.inode_mut(ino)?.mode = mode & 0o7777;
+        self.dirty = true;
+        Ok(())
+    }
+
+    pub fn set_mtime(&mut self, ino: u64, mtime: SystemTime) -> Result<(), Error> {
+        self.inode_mut(ino)?.mtime = mtime;
+        self.dirty = true;
+        Ok(())
+    }
+
+    fn check_name(name: &str) -> Result<(), Error> {
+        if name.is_empty()
+            || name.len() > MAX_NAME
+            || name.contains('/')
+            || name == "."
+            || name == ".."
+        {
+            return Err(Error::InvalidName);
+        }
+        Ok(())
+    }
+
+    fn insert(&mut self, parent: u64, name: &str, node: Node, mode: u16) -> Result<Attr, Error> {
+        FileSystem::check_name(name)?;
+        if self.entries(parent)?.contains_key(name) {
+            return Err(Error::Exists);
+        }
+        let ino = self.next_ino;
+        self.next_ino += 1;
+        self.inodes
+            .insert(ino, Inode::new(node, mode & 0o7777, parent));
+        self.entries_mut(parent)?.insert(name.to_string(), ino);
+        self.inode_mut(parent)?.mtime = SystemTime::now();
+        self.dirty = true;
+        self.attr(ino)
+    }
+
+    /// Create an empty file
+    pub fn create(&mut self, parent: u64, name: &str, mode: u16) -> Result<Attr, Error> {
+        self.insert(parent, name, Node::File(Vec::new()), mode)
+    }
+
+    pub fn mkdir(&mut self, parent: u64, name: &str, mode: u16) -> Result<Attr, Error> {
+        self.insert(parent, name, Node::Directory(BTreeMap::new()), mode)
+    }
+
+    /// Detach an entry from its directory, dropping the inode
+    fn remove(&mut self, parent: u64, name: &str) -> Result<(), Error> {
+        let ino = self
+            .entries_mut(parent)?
+            .remove(name)
+            .ok_or(Error::NotFound)?;
+        self.inodes.remove(&ino);
+        self.inode_mut(parent)?.mtime = SystemTime::now();
+        self.dirty = true;
+        Ok(())
+    }
+
+    /// Remove a file
+    pub fn unlink(&mut self, parent: u64, name: &str) -> Result<(), Error> {
+        if self.lookup(parent, name)?.kind == Kind::Directory {
+            return Err(Error::IsDirectory);
+        }
+        self.remove(parent, name)
+    }
+
+    /// Remove an empty directory
+    pub fn rmdir(&mut self, parent: u64, name: &str) -> Result<(), Error> {
+        let attr = self.lookup(parent, name)?;
+        if attr.kind != Kind::Directory {
+            return Err(Error::NotDirectory);
+        }
+        if attr.size != 0 {
+            return Err(Error::NotEmpty);
+        }
+        self.remove(parent, name)
+    }
+
+    /// Move an entry, replacing a file or empty directory of the same kind
+    /// already at the destination
+    pub fn rename(
+        &mut self,
+        parent: u64,
+        name: &str,
+        new_parent: u64,
+        new_name: &str,
+    ) -> Result<(), Error> {
+        let attr = self.lookup(parent, name)?;
+        FileSystem::check_name(new_name)?;
+        // A directory can't be moved underneath itself
+        let mut ancestor = new_parent;
+        loop {
+            if ancestor == attr.ino {
+                return Err(Error::InvalidName);
+            }
+            if ancestor == ROOT {
+                break;
+            }
+            ancestor = self.parent(ancestor)?;
+        }
+        match self.lookup(new_parent, new_name) {
+            Ok(existing) if existing.ino == attr.ino => return Ok(()),
+            Ok(existing) => match (attr.kind, existing.kind) {
+                (Kind::File, Kind::File) => self.unlink(new_parent, new_name)?,
+                (Kind::Directory, Kind::Directory) => self.rmdir(new_parent, new_name)?,
+                (Kind::File, Kind::Directory) => return Err(Error::IsDirectory),
+                (Kind::Directory, Kind::File) => return Err(Error::NotDirectory),
+            },
+            Err(Error::NotFound) => (),
+            Err(e) => return Err(e),
+        }
+        self.entries_mut(parent)?.remove(name);
+        self.entries_mut(new_parent)?
+            .insert(new_name.to_string(), attr.ino);
+        self.inode_mut(attr.ino)?.parent = new_parent;
+        let now = SystemTime::now();
+        self.inode_mut(parent)?.mtime = now;
+        self.inode_mut(new_parent)?.mtime = now;
+        self.dirty = true;
+        Ok(())
+    }
+}
+
+const KIND_FILE: u8 = 1;
+const KIND_DIRECTORY: u8 = 2;
+
+fn encode(inodes: &BTreeMap<u64, Inode>, next_ino: u64) -> Vec<u8> {
+    let mut out = Vec::new();
+    out.extend_from_slice(MAGIC);
+    out.extend_from_slice(&VERSION.to_be_bytes());
+    out.extend_from_slice(&next_ino.to_be_bytes());
+    out.extend_from_slice(&(inodes.len() as u64).to_be_bytes());
+    for (ino, inode) in inodes {
+        let mtime = inode.mtime.duration_since(UNIX_EPOCH).unwrap_or_default();
+        out.extend_from_slice(&ino.to_be_bytes());
+        out.extend_from_slice(&inode.mode.to_be_bytes());
+        out.extend_from_slice(&mtime.as_secs().to_be_bytes());
+        out.extend_from_slice(&mtime.subsec_nanos().to_be_bytes());
+        match &inode.node {
+            Node::File(contents) => {
+                out.push(KIND_FILE);
+                out.extend_from_slice(&(contents.len() as u64).to_be_bytes());
+                out.extend_from_slice(contents);
+            }
+            Node::Directory(entries) => {
+                out.push(KIND_DIRECTORY);
+                out.extend_from_slice(&(entries.len() as u64).to_be_bytes());
+                for (name, ino) in entries {
+                    out.extend_from_slice(&(name.len() as u16).to_be_bytes());
+                    out.extend_from_slice(name.as_bytes());
+                    out.extend_from_slice(&ino.to_be_bytes());
+                }
+            }
+        }
+    }
+    out
+}
+
+/// Consumes big-endian fields from the front of a buffer
+struct Fields<'a>(&'a [u8]);
+
+impl<'a> Fields<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+        let (head, tail) = self.0.split_at(n);
+        self.0 = tail;
+        Some(head)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        Some(u16::from_be_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        Some(u64::from_be_bytes(self.take(8)?.try_into().unwrap()))
+    }
+}
+
+fn decode(data: &[u8]) -> Option<(BTreeMap<u64, Inode>, u64)> {
+    let mut fields = Fields(data);
+    if fields.take(MAGIC.len())? != MAGIC || fields.u32()? != VERSION {
+        return None;
+    }
+    let next_ino = fields.u64()?;
+    let count = fields.u64()?;
+    let mut inodes = BTreeMap::new();
+    for _ in 0..count {
+        let ino = fields.u64()?;
+        let mode = fields.u16()?;
+        let (secs, nanos) = (fields.u64()?, fields.u32()?);
+        if nanos >= 1_000_000_000 {
+            return None;
+        }
+        let mtime = UNIX_EPOCH.checked_add(Duration::new(secs, nanos))?;
+        let node = match fields.u8()? {
+            KIND_FILE => {
+                let len = fields.u64()?;
+                Node::File(fields.take(usize::try_from(len).ok()?)?.to_vec())
+            }
+            KIND_DIRECTORY => {
+                let mut entries = BTreeMap::new();
+                for _ in 0..fields.u64()? {
+                    let len = fields.u16()? as usize;
+                    let name = String::from_utf8(fields.take(len)?.to_vec()).ok()?;
+                    entries.insert(name, fields.u64()?);
+                }
+                Node::Directory(entries)
+            }
+            _ => return None,
+        };
+        if ino >= next_ino
+            || inodes
+                .insert(
+                    ino,
+                    Inode {
+                        node,
+                        mode,
+                        mtime,
+                        parent: ROOT,
+                    },
+                )
+                .is_some()
+        {
+            return None;
+        }
+    }
+
+    // Every inode other than the root must be reachable from it exactly once,
+    // which also rules out directories containing each other
+    if !matches!(inodes.get(&ROOT)?.node, Node::Directory(_)) {
+        return None;
+    }
+    let mut parents = BTreeMap::new();
+    let mut pending = vec![ROOT];
+    while let Some(ino) = pending.pop() {
+        if let Node::Directory(entries) = &inodes[&ino].node {
+            for &child in entries.values() {
+                if child == ROOT
+                    || !inodes.contains_key(&child)
+                    || parents.insert(child, ino).is_some()
+                {
+                    return None;
+                }
+                pending.push(child);
+            }
+        }
+    }
+    if parents.len() != inodes.len() - 1 {
+        return None;
+    }
+    for (child, parent) in parents {
+        inodes.get_mut(&child)?.parent = parent;
+    }
+    Some((inodes, next_ino))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::BLOCK_SIZE;
+    use crate::test_util::new_cursor;
+
+    fn new_fs() -> FileSystem {
+        FileSystem::open(new_cursor(64, BLOCK_SIZE)).unwrap()
+    }
+
+    fn reopen(fs: FileSystem) -> FileSystem {
+        let extent = fs.into_inner().into_inner().unwrap().extent().clone();
+        let aspect = extent
+            .open_aspect(extent.keyword("hello".to_string()).unwrap())
+            .unwrap();
+        FileSystem::open(AspectCursor::new(aspect).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn files_persist() {
+        let mut fs = new_fs();
+        let dir = fs.mkdir(ROOT, "docs", 0o700).unwrap();
+        let file = fs.create(dir.ino, "notes.txt", 0o600).unwrap();
+        fs.write(file.ino, 0, b"hello").unwrap();
+        fs.write(file.ino, 3000, b"world").unwrap();
+        fs.flush().unwrap();
+        assert!(!fs.is_dirty());
+
+        let fs = reopen(fs);
+        let dir = fs.lookup(ROOT, "docs").unwrap();
+        assert_eq!(dir.kind, Kind::Directory);
+        assert_eq!(dir.mode, 0o700);
+        let file = fs.lookup(dir.ino, "notes.txt").unwrap();
+        assert_eq!(file.size, 3005);
+        assert_eq!(fs.read(file.ino, 0, 5).unwrap(), b"hello");
+        assert!(fs.read(file.ino, 5, 2995).unwrap().iter().all(|&b| b == 0));
+        assert_eq!(fs.read(file.ino, 3000, 100).unwrap(), b"world");
+        assert_eq!(fs.parent(file.ino).unwrap(), dir.ino);
+    }
+
+    #[test]
+    fn sizes_are_bounded() {
+        let mut fs = new_fs();
+        let file = fs.create(ROOT, "file", 0o644).unwrap();
+        fs.write(file.ino, 0, b"hello").unwrap();
+        assert!(matches!(fs.set_len(file.ino, 1 << 40), Err(Error::NoSpace)));
+        assert!(matches!(
+            fs.write(file.ino, 1 << 40, b"x"),
+            Err(Error::NoSpace)
+        ));
+        assert!(matches!(
+            fs.write(file.ino, u64::MAX - 1, b"xyz"),
+            Err(Error::TooLarge)
+        ));
+        assert!(matches!(fs.set_len(ROOT, 0), Err(Error::IsDirectory)));
+        assert_eq!(fs.attr(file.ino).unwrap().size, 5);
+
+        // Shrinking and growing within the free space still work
+        fs.set_len(file.ino, 2).unwrap();
+        fs.set_len(file.ino, 20000).unwrap();
+        assert_eq!(fs.read(file.ino, 0, 3).unwrap(), b"he\0");
+    }
+
+    #[test]
+    fn unflushed_changes_are_lost() {
+        let mut fs = new_fs();
+        fs.flush().unwrap();
+        fs.create(ROOT, "scratch", 0o644).unwrap();
+        let fs = reopen(fs);
+        assert!(fs.read_dir(ROOT).unwrap().is_empty());
+    }
+
+    #[test]
+    fn directory_rules() {
+        let mut fs = new_fs();
+        let dir = fs.mkdir(ROOT, "a", 0o755).unwrap();
+        fs.create(dir.ino, "f", 0o644).unwrap();
+        assert!(matches!(fs.mkdir(ROOT, "a", 0o755), Err(Error::Exists)));
+        assert!(matches!(
+            fs.create(ROOT, "x/y", 0o644),
+            Err(Error::InvalidName)
+        ));
+        assert!(matches!(fs.rmdir(ROOT, "a"), Err(Error::NotEmpty)));
+        assert!(matches!(fs.unlink(ROOT, "a"), Err(Error::IsDirectory)));
+        assert!(matches!(fs.rmdir(dir.ino, "f"), Err(Error::NotDirectory)));
+        assert!(matches!(
+            fs.rename(ROOT, "a", dir.ino, "b"),
+            Err(Error::InvalidName)
+        ));
+        for name in [".", ".."] {
+            assert!(matches!(
+                fs.rename(dir.ino, "f", dir.ino, name),
+                Err(Error::InvalidName)
+            ));
+        }
+        fs.unlink(dir.ino, "f").unwrap();
+        fs.rmdir(ROOT, "a").unwrap();
+        assert!(matches!(fs.lookup(ROOT, "a"), Err(Error::NotFound)));
+    }
+
+    #[test]
+    fn rename_replaces() {
+        let mut fs = new_fs();
+        let dir = fs.mkdir(ROOT, "dir", 0o755).unwrap();
+        let a = fs.create(ROOT, "a", 0o644).unwrap();
+        fs.write(a.ino, 0, b"a").unwrap();
+        let b = fs.create(dir.ino, "b", 0o644).unwrap();
+        fs.write(b.ino, 0, b"b").unwrap();
+
+        fs.rename(ROOT, "a", dir.ino, "b").unwrap();
+        assert!(matches!(fs.lookup(ROOT, "a"), Err(Error::NotFound)));
+        let moved = fs.lookup(dir.ino, "b").unwrap();
+        assert_eq!(moved.ino, a.ino);
+        assert_eq!(fs.read(moved.ino, 0, 10).unwrap(), b"a");
+        assert_eq!(fs.parent(moved.ino).unwrap(), dir.ino);
+        assert!(matches!(fs.attr(b.ino), Err(Error::NotFound)));
+    }
+
+    #[test]
+    fn corrupt_stream() {
+        let mut fs = new_fs();
+        fs.create(ROOT, "file", 0o644).unwrap();
+        fs.flush().unwrap();
+        let mut cursor = fs.into_inner();
+        cursor.seek(SeekFrom::Start(0)).unwrap();
+        cursor.write_all(b"XXXX").unwrap();
+        assert!(matches!(FileSystem::open(cursor), Err(Error::Corrupt)));
+
+        let data = encode(&new_fs().inodes, ROOT + 1);
+        assert!(decode(&data[..data.len() - 1]).is_none());
+
+        // The root's modification time, out of range for a `SystemTime`
+        let mtime = MAGIC.len() + 4 + 8 + 8 + 8 + 2;
+        let mut far = data.clone();
+        far[mtime..mtime + 8].copy_from_slice(&u64::MAX.to_be_bytes());
+        assert!(decode(&far).is_none());
+        let mut far = data.clone();
+        far[mtime + 8..mtime + 12].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert!(decode(&far).is_none());
+        assert!(decode(&data).is_some());
+
+        // Two directories holding each other, cut off from the root
+        let mut inodes = new_fs().inodes;
+        let directory =
+            |name: &str, ino| Node::Directory(BTreeMap::from([(name.to_string(), ino)]));
+        inodes.insert(2, Inode::new(directory("b", 3), 0o755, ROOT));
+        inodes.insert(3, Inode::new(directory("a", 2), 0o755, 2));
+        assert!(decode(&encode(&inodes, 4)).is_none());
+    }
+}
@@ -0,0 +1,849 @@
+use std::ffi::CString;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::aspect;
+use crate::fs::{self, Attr, FileSystem, Kind, MAX_NAME};
+
+/// Version of the kernel protocol spoken, as major and minor
+const FUSE_KERNEL_VERSION: u32 = 7;
+const FUSE_KERNEL_MINOR_VERSION: u32 = 31;
+
+/// Largest payload of a single write request
+const MAX_WRITE: usize = 128 * 1024;
+
+/// Room for the request header and arguments ahead of a write payload
+const REQUEST_OVERHEAD: usize = 4096;
+
+/// How long the kernel may cache names and attributes
+const TTL: Duration = Duration::from_secs(1);
+
+const FUSE_LOOKUP: u32 = 1;
+const FUSE_FORGET: u32 = 2;
+const FUSE_GETATTR: u32 = 3;
+const FUSE_SETATTR: u32 = 4;
+const FUSE_MKNOD: u32 = 8;
+const FUSE_MKDIR: u32 = 9;
+const FUSE_UNLINK: u32 = 10;
+const FUSE_RMDIR: u32 = 11;
+const FUSE_RENAME: u32 = 12;
+const FUSE_OPEN: u32 = 14;
+const FUSE_READ: u32 = 15;
+const FUSE_WRITE: u32 = 16;
+const FUSE_STATFS: u32 = 17;
+const FUSE_RELEASE: u32 = 18;
+const FUSE_FSYNC: u32 = 20;
+const FUSE_FLUSH: u32 = 25;
+const FUSE_INIT: u32 = 26;
+const FUSE_OPENDIR: u32 = 27;
+const FUSE_READDIR: u32 = 28;
+const FUSE_RELEASEDIR: u32 = 29;
+const FUSE_FSYNCDIR: u32 = 30;
+const FUSE_ACCESS: u32 = 34;
+const FUSE_CREATE: u32 = 35;
+const FUSE_INTERRUPT: u32 = 36;
+const FUSE_DESTROY: u32 = 38;
+const FUSE_BATCH_FORGET: u32 = 42;
+const FUSE_RENAME2: u32 = 45;
+
+const FATTR_MODE: u32 = 1 << 0;
+const FATTR_SIZE: u32 = 1 << 3;
+const FATTR_MTIME: u32 = 1 << 5;
+const FATTR_MTIME_NOW: u32 = 1 << 8;
+
+const FUSE_ASYNC_READ: u32 = 1 << 0;
+const FUSE_BIG_WRITES: u32 = 1 << 5;
+
+/// Size of `fuse_in_header`
+const IN_HEADER_SIZE: usize = 40;
+
+/// Size of `fuse_out_header`
+const OUT_HEADER_SIZE: usize = 16;
+
+/// Set by SIGINT or SIGTERM once [`stop_on_signals`] is in place
+static STOP: AtomicBool = AtomicBool::new(false);
+
+/// Mount a FUSE filesystem on `dir`, returning the channel to serve it on
+///
+/// Requires the privilege to mount filesystems. The mount is removed with
+/// `umount`, after which [`Session::run`] returns.
+pub fn mount<P: AsRef<Path>>(dir: P) -> io::Result<File> {
+    let channel = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/fuse")?;
+    let options = format!(
+        "fd={},rootmode=40000,user_id={},group_id={},default_permissions",
+        channel.as_raw_fd(),
+        unsafe { libc::getuid() },
+        unsafe { libc::getgid() },
+    );
+    let c_string =
+        |s: &[u8]| CString::new(s).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e));
+    let source = c_string(b"rubberhose")?;
+    let target = c_string(dir.as_ref().as_os_str().as_bytes())?;
+    let fstype = c_string(b"fuse.rubberhose")?;
+    let options = c_string(options.as_bytes())?;
+    let flags = libc::MS_NOSUID | libc::MS_NODEV;
+    if unsafe {
+        libc::mount(
+            source.as_ptr(),
+            target.as_ptr(),
+            fstype.as_ptr(),
+            flags,
+            options.as_ptr().cast(),
+        )
+    } != 0
+    {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(channel)
+}
+
+/// Remove a mount left behind by a [`Session::run`] that was stopped by a
+/// signal. A directory that is no longer mounted is left alone.
+pub fn unmount<P: AsRef<Path>>(dir: P) -> io::Result<()> {
+    let target = CString::new(dir.as_ref().as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    if unsafe { libc::umount2(target.as_ptr(), libc::MNT_DETACH) } != 0 {
+        let e = io::Error::last_os_error();
+        if e.raw_os_error() != Some(libc::EINVAL) {
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+extern "C" fn request_stop(_: libc::c_int) {
+    STOP.store(true, Ordering::SeqCst);
+}
+
+/// Make SIGINT and SIGTERM end [`Session::run`], which then flushes,
+/// instead of killing the process with the changes unwritten
+pub fn stop_on_signals() -> io::Result<()> {
+    for signal in [libc::SIGINT, libc::SIGTERM] {
+        let mut action: libc::sigaction = unsafe { std::mem::zeroed() };
+        // Without SA_RESTART a read blocked on the channel fails with EINTR
+        action.sa_sigaction = request_stop as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        if unsafe { libc::sigaction(signal, &action, std::ptr::null_mut()) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Map a filesystem error onto the errno reported to the kernel
+fn errno(e: &fs::Error) -> i32 {
+    match e {
+        fs::Error::NotFound => libc::ENOENT,
+        fs::Error::Exists => libc::EEXIST,
+        fs::Error::NotDirectory => libc::ENOTDIR,
+        fs::Error::IsDirectory => libc::EISDIR,
+        fs::Error::NotEmpty => libc::ENOTEMPTY,
+        fs::Error::InvalidName => libc::EINVAL,
+        fs::Error::Corrupt => libc::EIO,
+        fs::Error::NoSpace => libc::ENOSPC,
+        fs::Error::TooLarge => libc::EFBIG,
+        fs::Error::Io(e) => match e
+            .get_ref()
+            .and_then(|inner| inner.downcast_ref::<crate::Error>())
+        {
+            Some(e) if e.aspect() == Some(aspect::Error::ExtentFull) => libc::ENOSPC,
+            _ => libc::EIO,
+        },
+    }
+}
+
+/// The time `secs` seconds and `nanos` nanoseconds after the epoch, as the
+/// kernel sends it: `secs` is negative for times before 1970
+fn system_time(secs: i64, nanos: u32) -> Result<SystemTime, i32> {
+    if nanos >= 1_000_000_000 {
+        return Err(libc::EINVAL);
+    }
+    let time = if secs < 0 {
+        UNIX_EPOCH.checked_sub(Duration::from_secs(secs.unsigned_abs()))
+    } else {
+        UNIX_EPOCH.checked_add(Duration::from_secs(secs as u64))
+    };
+    time.and_then(|time| time.checked_add(Duration::from_nanos(nanos as u64)))
+        .ok_or(libc::EINVAL)
+}
+
+/// The inverse of `system_time`, saturating at the ends of the `i64` range
+fn timespec(time: SystemTime) -> (i64, u32) {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(after) => (
+            i64::try_from(after.as_secs()).unwrap_or(i64::MAX),
+            after.subsec_nanos(),
+        ),
+        Err(before) => {
+            let before = before.duration();
+            let secs = 0i64.saturating_sub_unsigned(before.as_secs());
+            match before.subsec_nanos() {
+                0 => (secs, 0),
+                nanos => (secs.saturating_sub(1), 1_000_000_000 - nanos),
+            }
+        }
+    }
+}
+
+/// Consumes native-endian request arguments from the front of a buffer
+struct Args<'a>(&'a [u8]);
+
+impl<'a> Args<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], i32> {
+        if self.0.len() < n {
+            return Err(libc::EINVAL);
+        }
+        let (head, tail) = self.0.split_at(n);
+        self.0 = tail;
+        Ok(head)
+    }
+
+    fn u32(&mut self) -> Result<u32, i32> {
+        Ok(u32::from_ne_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, i32> {
+        Ok(u64::from_ne_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    /// A NUL-terminated name
+    fn name(&mut self) -> Result<&'a str, i32> {
+        let len = self.0.iter().position(|&b| b == 0).ok_or(libc::EINVAL)?;
+        let name = self.take(len)?;
+        self.take(1)?;
+        if name.len() > MAX_NAME {
+            return Err(libc::ENAMETOOLONG);
+        }
+        std::str::from_utf8(name).map_err(|_| libc::EINVAL)
+    }
+}
+
+/// Builds the body of a reply
+#[derive(Default)]
+struct Reply(Vec<u8>);
+
+impl Reply {
+    fn u16(&mut self, value: u16) -> &mut Reply {
+        self.0.extend_from_slice(&value.to_ne_bytes());
+        self
+    }
+
+    fn u32(&mut self, value: u32) -> &mut Reply {
+        self.0.extend_from_slice(&value.to_ne_bytes());
+        self
+    }
+
+    fn u64(&mut self, value: u64) -> &mut Reply {
+        self.0.extend_from_slice(&value.to_ne_bytes());
+        self
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) -> &mut Reply {
+        self.0.extend_from_slice(bytes);
+        self
+    }
+
+    /// `fuse_attr`
+    fn attr(&mut self, attr: &Attr, uid: u32, gid: u32) -> &mut Reply {
+        let file_type = match attr.kind {
+            Kind::File => libc::S_IFREG,
+            Kind::Directory => libc::S_IFDIR,
+        };
+        let (secs, nanos) = timespec(attr.mtime);
+        self.u64(attr.ino)
+            .u64(attr.size)
+            .u64(attr.size.div_ceil(512))
+            // atime, mtime and ctime are all the modification time
+            .u64(secs as u64)
+            .u64(secs as u64)
+            .u64(secs as u64)
+            .u32(nanos)
+            .u32(nanos)
+            .u32(nanos)
+            .u32(file_type | attr.mode as u32)
+            .u32(attr.nlink)
+            .u32(uid)
+            .u32(gid)
+            .u32(0)
+            .u32(attr.blksize)
+            .u32(0)
+    }
+
+    /// `fuse_entry_out`
+    fn entry(&mut self, attr: &Attr, uid: u32, gid: u32) -> &mut Reply {
+        self.u64(attr.ino)
+            .u64(0)
+            .u64(TTL.as_secs())
+            .u64(TTL.as_secs())
+            .u32(0)
+            .u32(0)
+            .attr(attr, uid, gid)
+    }
+
+    /// `fuse_attr_out`
+    fn attr_out(&mut self, attr: &Attr, uid: u32, gid: u32) -> &mut Reply {
+        self.u64(TTL.as_secs()).u32(0).u32(0).attr(attr, uid, gid)
+    }
+
+    /// `fuse_open_out`, with no file handle as inode numbers suffice
+    fn open(&mut self) -> &mut Reply {
+        self.u64(0).u32(0).u32(0)
+    }
+
+    /// `fuse_dirent`, padded to 8 bytes
+    fn dirent(&mut self, ino: u64, offset: u64, name: &str, kind: Kind) -> &mut Reply {
+        let dirent_type = match kind {
+            Kind::File => libc::DT_REG,
+            Kind::Directory => libc::DT_DIR,
+        };
+        self.u64(ino)
+            .u64(offset)
+            .u32(name.len() as u32)
+            .u32(dirent_type as u32)
+            .bytes(name.as_bytes());
+        self.0.resize(self.0.len().next_multiple_of(8), 0);
+        self
+    }
+}
+
+/// Serves a [`FileSystem`] to the kernel
+///
+/// Requests are handled one at a time. Inode numbers are those of the
+/// filesystem and no file handles are used.
+pub struct Session {
+    fs: FileSystem,
+    uid: u32,
+    gid: u32,
+}
+
+impl Session {
+    pub fn new(fs: FileSystem) -> Session {
+        Session {
+            fs,
+            uid: unsafe { libc::getuid() },
+            gid: unsafe { libc::getgid() },
+        }
+    }
+
+    /// Release the filesystem, which may have unflushed changes
+    pub fn into_inner(self) -> FileSystem {
+        self.fs
+    }
+
+    /// Serve requests from a mounted channel until the filesystem is
+    /// unmounted, or a signal arrives after [`stop_on_signals`], then flush it
+    pub fn run(&mut self, channel: &mut File) -> Result<(), fs::Error> {
+        let mut buffer = vec![0; MAX_WRITE + REQUEST_OVERHEAD];
+        while !STOP.load(Ordering::SeqCst) {
+            let n = match channel.read(&mut buffer) {
+                Ok(n) => n,
+                Err(e) if e.raw_os_error() == Some(libc::ENODEV) => break,
+                // The request was interrupted before we read it
+                Err(e) if e.raw_os_error() == Some(libc::ENOENT) => continue,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            };
+            if let Some(reply) = self.handle(&buffer[..n]) {
+                match channel.write(&reply) {
+                    Ok(_) => (),
+                    // The request was interrupted while we handled it
+                    Err(e) if e.raw_os_error() == Some(libc::ENOENT) => (),
+                    Err(e) => return Err(e.into()),
+                }
+            }
+        }
+        self.fs.flush()
+    }
+
+    /// Handle a single request, returning the reply to send if any
+    pub fn handle(&mut self, request: &[u8]) -> Option<Vec<u8>> {
+        if request.len() < IN_HEADER_SIZE {
+            return None;
+        }
+        let mut header = Args(&request[..IN_HEADER_SIZE]);
+        let _len = header.u32().unwrap();
+        let opcode = header.u32().unwrap();
+        let unique = header.u64().unwrap();
+        let node = header.u64().unwrap();
+        log::debug!("FUSE request {} for inode {}", opcode, node);
+
+        let result = match opcode {
+            FUSE_FORGET | FUSE_BATCH_FORGET | FUSE_INTERRUPT => return None,
+            _ => self.dispatch(opcode, node, Args(&request[IN_HEADER_SIZE..])),
+        };
+        let (error, body) = match result {
+            Ok(reply) => (0, reply.0),
+            Err(errno) => (-errno, Vec::new()),
+        };
+        let mut out = Reply::default();
+        out.u32((OUT_HEADER_SIZE + body.len()) as u32)
+            .u32(error as u32)
+            .u64(unique)
+            .bytes(&body);
+        Some(out.0)
+    }
+
+    fn dispatch(&mut self, opcode: u32, node: u64, mut args: Args) -> Result<Reply, i32> {
+        let (uid, gid) = (self.uid, self.gid);
+        let mut reply = Reply::default();
+        let fs_error = |e: fs::Error| errno(&e);
+        match opcode {
+            FUSE_INIT => {
+                let major = args.u32()?;
+                let _minor = args.u32()?;
+                let max_readahead = args.u32()?;
+                reply
+                    .u32(FUSE_KERNEL_VERSION)
+                    .u32(FUSE_KERNEL_MINOR_VERSION);
+                // A newer kernel retries with our version
+                if major == FUSE_KERNEL_VERSION {
+                    reply
+                        .u32(max_readahead)
+                        .u32(FUSE_ASYNC_READ | FUSE_BIG_WRITES)
+                        .u16(0)
+                        .u16(0)
+                        .u32(MAX_WRITE as u32)
+                        .u32(1)
+                        .u16(0)
+                        .u16(0)
+                        .u32(0)
+                        .bytes(&[0; 28]);
+                }
+            }
+            FUSE_DESTROY => self.fs.flush().map_err(fs_error)?,
+            FUSE_LOOKUP => {
+                let attr = self.fs.lookup(node, args.name()?).map_err(fs_error)?;
+                reply.entry(&attr, uid, gid);
+            }
+            FUSE_GETATTR => {
+                reply.attr_out(&self.fs.attr(node).map_err(fs_error)?, uid, gid);
+            }
+            FUSE_SETATTR => {
+                let valid = args.u32()?;
+                args.u32()?;
+                let _fh = args.u64()?;
+                let size = args.u64()?;
+                let _lock_owner = args.u64()?;
+                let _atime = args.u64()?;
+                let mtime = args.u64()? as i64;
+                let _ctime = args.u64()?;
+                let _atimensec = args.u32()?;
+                let mtimensec = args.u32()?;
+                let _ctimensec = args.u32()?;
+                let mode = args.u32()?;
+                let mtime = if valid & FATTR_MTIME_NOW != 0 {
+                    Some(SystemTime::now())
+                } else if valid & FATTR_MTIME != 0 {
+                    Some(system_time(mtime, mtimensec)?)
+                } else {
+                    None
+                };
+                // Resizing is the only change that can fail, so make it first
+                if valid & FATTR_SIZE != 0 {
+                    self.fs.set_len(node, size).map_err(fs_error)?;
+                }
+                if valid & FATTR_MODE != 0 {
+                    self.fs.set_mode(node, mode as u16).map_err(fs_error)?;
+                }
+                if let Some(mtime) = mtime {
+                    self.fs.set_mtime(node, mtime).map_err(fs_error)?;
+                }
+                reply.attr_out(&self.fs.attr(node).map_err(fs_error)?, uid, gid);
+            }
+            FUSE_MKNOD => {
+                let mode = args.u32()?;
+                let _rdev = args.u32()?;
+                args.take(8)?;
+                if mode & libc::S_IFMT != libc::S_IFREG {
+                    return Err(libc::EPERM);
+                }
+                let attr = self
+                    .fs
+                    .create(node, args.name()?, mode as u16)
+                    .map_err(fs_error)?;
+                reply.entry(&attr, uid, gid);
+            }
+            FUSE_MKDIR => {
+                let mode = args.u32()?;
+                let _umask = args.u32()?;
+                let attr = self
+                    .fs
+                    .mkdir(node, args.name()?, mode as u16)
+                    .map_err(fs_error)?;
+                reply.entry(&attr, uid, gid);
+            }
+            FUSE_CREATE => {
+                let _flags = args.u32()?;
+                let mode = args.u32()?;
+                args.take(8)?;
+                let attr = self
+                    .fs
+                    .create(node, args.name()?, mode as u16)
+                    .map_err(fs_error)?;
+                reply.entry(&attr, uid, gid).open();
+            }
+            FUSE_UNLINK => self.fs.unlink(node, args.name()?).map_err(fs_error)?,
+            FUSE_RMDIR => self.fs.rmdir(node, args.name()?).map_err(fs_error)?,
+            FUSE_RENAME | FUSE_RENAME2 => {
+                let new_parent = args.u64()?;
+                if opcode == FUSE_RENAME2 {
+                    // Neither RENAME_NOREPLACE nor RENAME_EXCHANGE are supported
+                    if args.u32()? != 0 {
+                        return Err(libc::EINVAL);
+                    }
+                    args.u32()?;
+                }
+                let name = args.name()?;
+                let new_name = args.name()?;
+                self.fs
+                    .rename(node, name, new_parent, new_name)
+                    .map_err(fs_error)?;
+            }
+            FUSE_OPEN | FUSE_OPENDIR => {
+                self.fs.attr(node).map_err(fs_error)?;
+                reply.open();
+            }
+            FUSE_READ => {
+                let _fh = args.u64()?;
+                let offset = args.u64()?;
+                let size = args.u32()?;
+                reply.bytes(
+                    self.fs
+                        .read(node, offset, size as usize)
+                        .map_err(fs_error)?,
+                );
+            }
+            FUSE_WRITE => {
+                let _fh = args.u64()?;
+                let offset = args.u64()?;
+                let size = args.u32()?;
+                args.take(20)?;
+                let data = args.take(size as usize)?;
+                self.fs.write(node, offset, data).map_err(fs_error)?;
+                reply.u32(size).u32(0);
+            }
+            FUSE_READDIR => {
+                let _fh = args.u64()?;
+                let offset = args.u64()?;
+                let size = args.u32()? as usize;
+                let parent = self.fs.parent(node).map_err(fs_error)?;
+                let mut entries = vec![
+                    (".".to_string(), node, Kind::Directory),
+                    ("..".to_string(), parent, Kind::Directory),
+                ];
+                for (name, attr) in self.fs.read_dir(node).map_err(fs_error)? {
+                    entries.push((name, attr.ino, attr.kind));
+                }
+                for (i, (name, ino, kind)) in entries.iter().enumerate().skip(offset as usize) {
+                    let mut entry = Reply::default();
+                    entry.dirent(*ino, i as u64 + 1, name, *kind);
+                    if reply.0.len() + entry.0.len() > size {
+                        break;
+                    }
+                    reply.bytes(&entry.0);
+                }
+            }
+            FUSE_STATFS => {
+                // Only what the aspect itself could tell: the image's size
+                // and the blocks this aspect holds
+                let aspect = self.fs.cursor().get_ref();
+                let total = aspect.extent().n_blocks();
+                let free = total.saturating_sub(aspect.len());
+                let data_size = aspect.data_size() as u32;
+                reply
+                    .u64(total)
+                    .u64(free)
+                    .u64(free)
+                    .u64(0)
+                    .u64(0)
+                    .u32(data_size)
+                    .u32(MAX_NAME as u32)
+                    .u32(data_size)
+                    .u32(0)
+                    .bytes(&[0; 24]);
+            }
+            FUSE_FSYNC | FUSE_FSYNCDIR => self.fs.flush().map_err(fs_error)?,
+            FUSE_RELEASE | FUSE_RELEASEDIR | FUSE_FLUSH | FUSE_ACCESS => (),
+            _ => return Err(libc::ENOSYS),
+        }
+        Ok(reply)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::io::FromRawFd;
+
+    use crate::block::BLOCK_SIZE;
+    use crate::fs::ROOT;
+    use crate::test_util::new_cursor;
+
+    /// Plays the kernel's side of the protocol against a session
+    struct Harness {
+        session: Session,
+        unique: u64,
+    }
+
+    impl Harness {
+        fn new() -> Harness {
+            let fs = FileSystem::open(new_cursor(64, BLOCK_SIZE)).unwrap();
+            let mut harness = Harness {
+                session: Session::new(fs),
+                unique: 0,
+            };
+            let mut init = Reply::default();
+            init.u32(7).u32(31).u32(65536).u32(0);
+            let reply = harness.request(FUSE_INIT, 0, &init.0).unwrap();
+            assert_eq!(
+                &reply[..8],
+                &[7u32.to_ne_bytes(), 31u32.to_ne_bytes()].concat()
+            );
+            harness
+        }
+
+        /// Send a request, returning the reply body or the errno
+        fn request(&mut self, opcode: u32, node: u64, args: &[u8]) -> Result<Vec<u8>, i32> {
+            self.unique += 1;
+            let mut request = Reply::default();
+            request
+                .u32((IN_HEADER_SIZE + args.len()) as u32)
+                .u32(opcode)
+                .u64(self.unique)
+                .u64(node)
+                .bytes(&[0; 16])
+                .bytes(args);
+            let reply = self.session.handle(&request.0).unwrap();
+            let mut fields = Args(&reply);
+            assert_eq!(fields.u32().unwrap() as usize, reply.len());
+            let error = fields.u32().unwrap() as i32;
+            assert_eq!(fields.u64().unwrap(), self.unique);
+            match error {
+                0 => Ok(fields.0.to_vec()),
+                _ => Err(-error),
+            }
+        }
+
+        /// The inode number and mode out of an entry reply
+        fn entry(reply: &[u8]) -> (u64, u32) {
+            let mut fields = Args(reply);
+            let ino = fields.u64().unwrap();
+            fields.take(32 + 60).unwrap();
+            (ino, fields.u32().unwrap())
+        }
+    }
+
+    fn name(name: &str) -> Vec<u8> {
+        [name.as_bytes(), &[0]].concat()
+    }
+
+    #[test]
+    fn create_write_read() {
+        let mut h = Harness::new();
+        let mut create = Reply::default();
+        create
+            .u32(0)
+            .u32(0o100644)
+            .u32(0o022)
+            .u32(0)
+            .bytes(&name("file"));
+        let reply = h.request(FUSE_CREATE, ROOT, &create.0).unwrap();
+        assert_eq!(reply.len(), 128 + 16);
+        let (ino, mode) = Harness::entry(&reply);
+        assert_eq!(mode, libc::S_IFREG | 0o644);
+
+        let mut write = Reply::default();
+        write
+            .u64(0)
+            .u64(10)
+            .u32(5)
+            .u32(0)
+            .u64(0)
+            .u32(0)
+            .u32(0)
+            .bytes(b"hello");
+        let reply = h.request(FUSE_WRITE, ino, &write.0).unwrap();
+        assert_eq!(Args(&reply).u32().unwrap(), 5);
+
+        let mut read = Reply::default();
+        read.u64(0).u64(8).u32(100).u32(0).u64(0).u32(0).u32(0);
+        assert_eq!(h.request(FUSE_READ, ino, &read.0).unwrap(), b"\0\0hello");
+
+        let reply = h.request(FUSE_LOOKUP, ROOT, &name("file")).unwrap();
+        assert_eq!(Harness::entry(&reply).0, ino);
+        assert_eq!(
+            h.request(FUSE_LOOKUP, ROOT, &name("missing")),
+            Err(libc::ENOENT)
+        );
+        assert_eq!(h.request(FUSE_CREATE, ROOT, &create.0), Err(libc::EEXIST));
+    }
+
+    #[test]
+    fn directories() {
+        let mut h = Harness::new();
+        let mut mkdir = Reply::default();
+        mkdir.u32(0o755).u32(0).bytes(&name("dir"));
+        let (dir, _) = Harness::entry(&h.request(FUSE_MKDIR, ROOT, &mkdir.0).unwrap());
+
+        let mut rename = Reply::default();
+        rename.u64(ROOT).bytes(&name("dir")).bytes(&name("renamed"));
+        h.request(FUSE_RENAME, ROOT, &rename.0).unwrap();
+
+        let mut readdir = Reply::default();
+        readdir.u64(0).u64(0).u32(4096).u32(0).u64(0).u32(0).u32(0);
+        let reply = h.request(FUSE_READDIR, ROOT, &readdir.0).unwrap();
+        let mut fields = Args(&reply);
+        let mut names = Vec::new();
+        while !fields.0.is_empty() {
+            let ino = fields.u64().unwrap();
+            let _offset = fields.u64().unwrap();
+            let len = fields.u32().unwrap() as usize;
+            fields.u32().unwrap();
+            names.push((
+                ino,
+                std::str::from_utf8(fields.take(len).unwrap())
+                    .unwrap()
+                    .to_string(),
+            ));
+            fields.take((8 - len % 8) % 8).unwrap();
+        }
+        assert_eq!(
+            names,
+            vec![
+                (ROOT, ".".to_string()),
+                (ROOT, "..".to_string()),
+                (dir, "renamed".to_string())
+            ]
+        );
+
+        // Resuming past the end yields nothing
+        let mut readdir = Reply::default();
+        readdir.u64(0).u64(3).u32(4096).u32(0).u64(0).u32(0).u32(0);
+        assert!(h
+            .request(FUSE_READDIR, ROOT, &readdir.0)
+            .unwrap()
+            .is_empty());
+
+        h.request(FUSE_RMDIR, ROOT, &name("renamed")).unwrap();
+        assert_eq!(h.request(FUSE_GETATTR, dir, &[0; 16]), Err(libc::ENOENT));
+    }
+
+    #[test]
+    fn truncate_and_sync() {
+        let mut h = Harness::new();
+        let mut create = Reply::default();
+        create
+            .u32(0)
+            .u32(0o100600)
+            .u32(0)
+            .u32(0)
+            .bytes(&name("file"));
+        let (ino, _) = Harness::entry(&h.request(FUSE_CREATE, ROOT, &create.0).unwrap());
+
+        let mut setattr = Reply::default();
+        setattr
+            .u32(FATTR_SIZE)
+            .u32(0)
+            .u64(0)
+            .u64(3000)
+            .bytes(&[0; 64]);
+        let reply = h.request(FUSE_SETATTR, ino, &setattr.0).unwrap();
+        assert_eq!(
+            Args(&reply[16..]).take(16).unwrap()[8..],
+            3000u64.to_ne_bytes()
+        );
+
+        assert!(h.session.fs.is_dirty());
+        h.request(FUSE_FSYNC, ino, &[0; 16]).unwrap();
+        assert!(!h.session.fs.is_dirty());
+        assert_eq!(h.request(0xffff, ROOT, &[]), Err(libc::ENOSYS));
+        assert!(h.session.handle(&[0; 8]).is_none());
+    }
+
+    #[test]
+    fn mtime_before_epoch() {
+        let mut h = Harness::new();
+        let setattr = |mode: u32, mtime: i64, nanos: u32| {
+            let mut setattr = Reply::default();
+            setattr
+                .u32(FATTR_MODE | FATTR_MTIME)
+                .u32(0)
+                .bytes(&[0; 32])
+                .u64(mtime as u64)
+                .u64(0)
+                .u32(0)
+                .u32(nanos)
+                .u32(0)
+                .u32(mode)
+                .bytes(&[0; 16]);
+            setattr.0
+        };
+        let reply = h
+            .request(
+                FUSE_SETATTR,
+                ROOT,
+                &setattr(0o700, -1_500_000_000, 250_000_000),
+            )
+            .unwrap();
+        let mut attr = Args(&reply[16..]);
+        attr.take(32).unwrap();
+        assert_eq!(attr.u64().unwrap() as i64, -1_500_000_000);
+        attr.take(12).unwrap();
+        assert_eq!(attr.u32().unwrap(), 250_000_000);
+        assert_eq!(
+            h.session.fs.attr(ROOT).unwrap().mtime,
+            UNIX_EPOCH - Duration::new(1_499_999_999, 750_000_000)
+        );
+
+        // A bad time is refused before anything else is changed
+        assert_eq!(
+            h.request(FUSE_SETATTR, ROOT, &setattr(0o755, 0, 1_000_000_000)),
+            Err(libc::EINVAL)
+        );
+        assert_eq!(h.session.fs.attr(ROOT).unwrap().mode, 0o700);
+
+        // As is a mode change along with a size a directory can't take
+        let mut setattr = Reply::default();
+        setattr
+            .u32(FATTR_SIZE | FATTR_MODE)
+            .u32(0)
+            .u64(0)
+            .u64(10)
+            .bytes(&[0; 44])
+            .u32(0o755)
+            .bytes(&[0; 16]);
+        assert_eq!(h.request(FUSE_SETATTR, ROOT, &setattr.0), Err(libc::EISDIR));
+        assert_eq!(h.session.fs.attr(ROOT).unwrap().mode, 0o700);
+    }
+
+    #[test]
+    fn signal_stops_and_flushes() {
+        let mut h = Harness::new();
+        let mut mkdir = Reply::default();
+        mkdir.u32(0o755).u32(0).bytes(&name("dir"));
+        h.request(FUSE_MKDIR, ROOT, &mkdir.0).unwrap();
+        assert!(h.session.fs.is_dirty());
+
+        // Nothing is ever written to the channel, so only the signal ends the run
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        let (mut channel, _writer) =
+            unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+        stop_on_signals().unwrap();
+        unsafe { libc::raise(libc::SIGTERM) };
+        h.session.run(&mut channel).unwrap();
+        assert!(!h.session.fs.is_dirty());
+
+        for signal in [libc::SIGINT, libc::SIGTERM] {
+            unsafe { libc::signal(signal, libc::SIG_DFL) };
+        }
+        STOP.store(false, Ordering::SeqCst);
+    }
+}
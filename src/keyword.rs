@@ -1,9 +1,111 @@
-use openssl::sha::sha256;
+use openssl::error::ErrorStack;
+use openssl::hash::MessageDigest;
+use openssl::pkcs5::scrypt;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
 use std::{fmt, mem};
 
 /// The hashed, secure key
 pub type Key = [u8; 32];
 
+/// Per-volume salt mixed into the key derivation
+pub type Salt = [u8; 32];
+
+/// HKDF salt, fixed as the secret is already salted by scrypt
+pub(crate) const HKDF_SALT: &[u8] = b"rubberhose";
+
+/// HMAC-SHA256 over the concatenation of `parts`
+pub(crate) fn hmac(key: &[u8], parts: &[&[u8]]) -> Result<Key, ErrorStack> {
+    let key = PKey::hmac(key)?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+    for part in parts {
+        signer.update(part)?;
+    }
+    let mut mac = [0; 32];
+    signer.sign(&mut mac)?;
+    Ok(mac)
+}
+
+/// HKDF-SHA256 (RFC 5869), filling all of `okm`
+pub(crate) fn hkdf(salt: &[u8], ikm: &[u8], info: &[u8], okm: &mut [u8]) -> Result<(), ErrorStack> {
+    let prk = hmac(salt, &[ikm])?;
+    let mut t: &[u8] = &[];
+    let mut block;
+    for (i, chunk) in okm.chunks_mut(32).enumerate() {
+        block = hmac(&prk, &[t, info, &[i as u8 + 1]])?;
+        chunk.copy_from_slice(&block[..chunk.len()]);
+        t = &block;
+    }
+    Ok(())
+}
+
+/// The keys protecting a single block: a pair of AES-256 keys for XTS, and a
+/// key for the block's tags
+pub struct BlockKey {
+    cipher: [u8; 64],
+    mac: Key,
+}
+
+impl BlockKey {
+    /// Expand a secret into a block key, separated by `info`
+    pub(crate) fn derive(secret: &[u8], info: &[u8]) -> Result<BlockKey, ErrorStack> {
+        let mut okm = [0; 96];
+        hkdf(HKDF_SALT, secret, info, &mut okm)?;
+        let (cipher, mac) = okm.split_at(64);
+        Ok(BlockKey {
+            cipher: cipher.try_into().unwrap(),
+            mac: mac.try_into().unwrap(),
+        })
+    }
+
+    pub fn cipher(&self) -> &[u8; 64] {
+        &self.cipher
+    }
+
+    pub fn mac(&self) -> &Key {
+        &self.mac
+    }
+}
+
+/// Cost parameters for the scrypt key derivation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KdfParams {
+    /// log2 of the scrypt CPU/memory cost
+    pub log_n: u8,
+    /// scrypt block size
+    pub r: u32,
+    /// scrypt parallelism
+    pub p: u32,
+}
+
+impl KdfParams {
+    /// Roughly 32 MiB and a tenth of a second per derivation
+    pub const DEFAULT: KdfParams = KdfParams {
+        log_n: 15,
+        r: 8,
+        p: 1,
+    };
+
+    /// Bytes of memory a derivation needs
+    pub fn memory(&self) -> u64 {
+        128 * self.r as u64 * self.p as u64 * (1 << self.log_n)
+    }
+}
+
+/// Cheap parameters, so the tests don't spend their time in scrypt
+#[cfg(test)]
+pub(crate) const TEST_KDF_PARAMS: KdfParams = KdfParams {
+    log_n: 4,
+    r: 8,
+    p: 1,
+};
+
+impl Default for KdfParams {
+    fn default() -> KdfParams {
+        KdfParams::DEFAULT
+    }
+}
+
 /// A combination of the text and hash of a given key. Needed for decryption
 pub struct Keyword {
     text: String,
@@ -11,12 +113,26 @@ pub struct Keyword {
 }
 
 impl Keyword {
-    /// Create a new keyword from a given string
-    pub fn new(text: String) -> Keyword {
-        Keyword {
-            hash: sha256(text.as_bytes()),
-            text: text,
-        }
+    /// Create a new keyword from a given string, stretching it with scrypt
+    pub fn new(text: String, salt: &[u8], params: KdfParams) -> Result<Keyword, ErrorStack> {
+        Ok(Keyword {
+            hash: Keyword::stretch(text.as_bytes(), salt, params)?,
+            text,
+        })
+    }
+
+    fn stretch(text: &[u8], salt: &[u8], params: KdfParams) -> Result<Key, ErrorStack> {
+        let mut hash = [0; 32];
+        scrypt(
+            text,
+            salt,
+            1 << params.log_n,
+            params.r as u64,
+            params.p as u64,
+            params.memory() + 1024 * 1024,
+            &mut hash,
+        )?;
+        Ok(hash)
     }
 
     pub fn text(&self) -> &str {
@@ -27,13 +143,28 @@ impl Keyword {
         &self.hash
     }
 
-    pub fn seed_index(&self, n_blocks: u64) -> u64 {
-        (
-            self.slice_int::<u64>(0).unwrap() ^
-            self.slice_int::<u64>(8).unwrap() ^
-            self.slice_int::<u64>(16).unwrap() ^
-            self.slice_int::<u64>(24).unwrap()
-        ) % n_blocks
+    /// Derive an independent key for a single purpose from the stretched hash
+    fn subkey(&self, label: &[u8]) -> Result<Key, ErrorStack> {
+        let mut key = [0; 32];
+        hkdf(HKDF_SALT, &self.hash, label, &mut key)?;
+        Ok(key)
+    }
+
+    pub fn seed_index(&self, n_blocks: u64) -> Result<u64, ErrorStack> {
+        let key = self.subkey(b"rubberhose seed index")?;
+        Ok(key.chunks(8).map(u64::from_bytes).fold(0, |acc, x| acc ^ x) % n_blocks)
+    }
+
+    /// Key the allocator scatters this keyword's aspect with
+    pub fn alloc_key(&self) -> Result<Key, ErrorStack> {
+        self.subkey(b"rubberhose allocation")
+    }
+
+    /// Key used to encrypt block `block_id` of this keyword's aspect
+    pub fn block_key(&self, block_id: u64) -> Result<BlockKey, ErrorStack> {
+        let mut info = b"rubberhose block key ".to_vec();
+        info.extend_from_slice(&block_id.to_be_bytes());
+        BlockKey::derive(&self.hash, &info)
     }
 
     /// A pretty form of the hash
@@ -57,15 +188,12 @@ impl Keyword {
         if index + length > self.hash.len() {
             return None;
         }
-        return Some(&self.hash[index..index + length]);
+        Some(&self.hash[index..index + length])
     }
     /// Return a int, read as an unsigned, big-endian integer starting at offset
     pub fn slice_int<T: FromByteSlice>(&self, offset: usize) -> Option<T> {
-        if let Some(slice) = self.get_slice(offset, mem::size_of::<T>()) {
-            return Some(T::from_bytes(slice));
-        } else {
-            return None;
-        }
+        self.get_slice(offset, mem::size_of::<T>())
+            .map(|slice| T::from_bytes(slice))
     }
 
     // Slice shorthands
@@ -101,7 +229,7 @@ pub trait FromByteSlice {
 
 impl FromByteSlice for u8 {
     fn from_bytes(bytes: &[u8]) -> u8 {
-        return bytes[0];
+        bytes[0]
     }
 }
 impl FromByteSlice for u16 {
@@ -129,40 +257,141 @@ impl FromByteSlice for u128 {
 mod tests {
     use super::*;
 
-    fn disp_hash(text: String) {
-        let k = Keyword::new(text);
-        println!("{} -> {:?}", k.text, k.hash);
+    const SALT: Salt = [0x5a; 32];
+    const PARAMS: KdfParams = TEST_KDF_PARAMS;
+
+    fn keyword(text: &str) -> Keyword {
+        Keyword::new(text.to_string(), &SALT, PARAMS).unwrap()
     }
 
     /// Returns a keyword with a specific pattern in the fingerprint, useful
     /// for testing slicing
     fn fingerprint_hash() -> Keyword {
-        let mut k = Keyword::new("a".to_string());
+        let mut k = keyword("a");
         k.hash = [
             0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b, 0x0c, 0x0d,
             0x0e, 0x0f, 0x10, 0x11, 0x12, 0x13, 0x14, 0x15, 0x16, 0x17, 0x18, 0x19, 0x1a, 0x1b,
             0x1c, 0x1d, 0x1e, 0x1f,
         ];
-        return k;
+        k
     }
 
+    /// Known answers for the whole derivation, computed independently with
+    /// Python's hashlib.scrypt and an RFC 5869 HKDF
     #[test]
-    fn hash_simple() {
-        disp_hash("test".to_string());
-        disp_hash("test1".to_string());
+    fn derivation_vectors() {
+        let k = keyword("test");
+        assert_eq!(
+            k.hash,
+            [
+                0x40, 0xa0, 0xd5, 0x2c, 0x63, 0xf2, 0xdd, 0x5c, 0x3f, 0x09, 0x08, 0xd3, 0xce, 0xf7,
+                0x3d, 0xf6, 0x00, 0x7b, 0x5d, 0x76, 0xc6, 0xdb, 0x82, 0x68, 0x44, 0xcc, 0xc1, 0x8b,
+                0x1b, 0x0c, 0x6e, 0x10,
+            ]
+        );
+        assert_eq!(
+            k.block_key(0).unwrap().mac(),
+            &[
+                0x21, 0xe0, 0x8a, 0x7b, 0x07, 0xe9, 0x81, 0x19, 0x23, 0x22, 0x75, 0xde, 0xb5, 0xe0,
+                0xd1, 0xfe, 0x3b, 0x3f, 0x53, 0x6e, 0xfa, 0x1a, 0xc9, 0x71, 0x10, 0xd8, 0xf6, 0xb1,
+                0x95, 0x52, 0xc1, 0xc0,
+            ]
+        );
+        assert_eq!(k.seed_index(1000003).unwrap(), 735901);
     }
 
     #[test]
-    fn sha_test() {
-        let k = Keyword::new("test".to_string());
+    fn scrypt_test() {
+        // RFC 7914 test vector, truncated to the key length
+        let k = Keyword::new(
+            "password".to_string(),
+            b"NaCl",
+            KdfParams {
+                log_n: 10,
+                r: 8,
+                p: 16,
+            },
+        )
+        .unwrap();
         let expected = [
-            0x9f, 0x86, 0xd0, 0x81, 0x88, 0x4c, 0x7d, 0x65, 0x9a, 0x2f, 0xea, 0xa0, 0xc5, 0x5a,
-            0xd0, 0x15, 0xa3, 0xbf, 0x4f, 0x1b, 0x2b, 0x0b, 0x82, 0x2c, 0xd1, 0x5d, 0x6c, 0x15,
-            0xb0, 0xf0, 0x0a, 0x08,
+            0xfd, 0xba, 0xbe, 0x1c, 0x9d, 0x34, 0x72, 0x00, 0x78, 0x56, 0xe7, 0x19, 0x0d, 0x01,
+            0xe9, 0xfe, 0x7c, 0x6a, 0xd7, 0xcb, 0xc8, 0x23, 0x78, 0x30, 0xe7, 0x73, 0x76, 0x63,
+            0x4b, 0x37, 0x31, 0x62,
         ];
         assert_eq!(k.hash, expected);
     }
 
+    #[test]
+    fn salt_and_params_matter() {
+        let k = keyword("test");
+        assert_ne!(
+            Keyword::new("test".to_string(), &[0; 32], PARAMS)
+                .unwrap()
+                .hash,
+            k.hash
+        );
+        assert_ne!(
+            Keyword::new("test".to_string(), &SALT, KdfParams { log_n: 5, ..PARAMS })
+                .unwrap()
+                .hash,
+            k.hash
+        );
+        assert_eq!(keyword("test").hash, k.hash);
+    }
+
+    #[test]
+    fn hkdf_test() {
+        // RFC 5869 test case 1
+        let mut okm = [0; 42];
+        hkdf(
+            &[
+                0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b, 0x0c,
+            ],
+            &[0x0b; 22],
+            &[0xf0, 0xf1, 0xf2, 0xf3, 0xf4, 0xf5, 0xf6, 0xf7, 0xf8, 0xf9],
+            &mut okm,
+        )
+        .unwrap();
+        let expected = [
+            0x3c, 0xb2, 0x5f, 0x25, 0xfa, 0xac, 0xd5, 0x7a, 0x90, 0x43, 0x4f, 0x64, 0xd0, 0x36,
+            0x2f, 0x2a, 0x2d, 0x2d, 0x0a, 0x90, 0xcf, 0x1a, 0x5a, 0x4c, 0x5d, 0xb0, 0x2d, 0x56,
+            0xec, 0xc4, 0xc5, 0xbf, 0x34, 0x00, 0x72, 0x08, 0xd5, 0xb8, 0x87, 0x18, 0x58, 0x65,
+        ];
+        assert_eq!(okm, expected);
+    }
+
+    #[test]
+    fn subkeys_are_separated() {
+        let k = keyword("test");
+        assert_ne!(k.block_key(0).unwrap().mac(), &k.hash);
+        assert_ne!(
+            k.block_key(0).unwrap().mac(),
+            &k.subkey(b"rubberhose seed index").unwrap()
+        );
+        assert!(k.seed_index(1000).unwrap() < 1000);
+    }
+
+    #[test]
+    fn block_keys_are_unique() {
+        let k = keyword("test");
+        let other = keyword("test1");
+        assert_ne!(
+            k.block_key(0).unwrap().cipher(),
+            k.block_key(1).unwrap().cipher()
+        );
+        assert_ne!(k.block_key(0).unwrap().mac(), k.block_key(1).unwrap().mac());
+        assert_ne!(
+            k.block_key(0).unwrap().cipher(),
+            other.block_key(0).unwrap().cipher()
+        );
+        assert_eq!(
+            k.block_key(7).unwrap().cipher(),
+            keyword("test").block_key(7).unwrap().cipher()
+        );
+        let key = k.block_key(0).unwrap();
+        assert_ne!(key.cipher()[..32], key.cipher()[32..]);
+    }
+
     #[test]
     fn slice_u8() {
         let k = fingerprint_hash();
@@ -347,7 +576,7 @@ mod tests {
 
     #[test]
     fn out_of_bounds_slice() {
-        let k = Keyword::new("test".to_string());
+        let k = keyword("test");
         assert_eq!(k.slice_int::<u8>(0x20), None);
         assert_eq!(k.slice_int::<u16>(0x1f), None);
         assert_eq!(k.slice_int::<u32>(0x1d), None);
@@ -0,0 +1,29 @@
+extern crate bitvec;
+extern crate log;
+extern crate memmap2;
+extern crate openssl;
+
+pub mod allocator;
+pub mod aspect;
+pub mod block;
+mod crc;
+pub mod cursor;
+pub mod device;
+pub mod error;
+pub mod extent;
+pub mod fs;
+pub mod fuse;
+pub mod keyword;
+pub mod nbd;
+pub mod seed;
+pub mod snapshot;
+#[cfg(test)]
+mod test_util;
+
+pub use aspect::Aspect;
+pub use block::{Block, EncryptedBlock, RawBlock};
+pub use cursor::AspectCursor;
+pub use device::{BlockDevice, MmapImage, RAMDisk, RawDevice};
+pub use error::{Error, Result};
+pub use extent::{Extent, ExtentHandle};
+pub use keyword::{Key, Keyword};
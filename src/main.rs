@@ -1,6 +1,37 @@
+use std::process::exit;
 
+mod cli;
+
+fn run() -> Result<(), cli::Error> {
+    let mut args = std::env::args().skip(1);
+    let command = args
+        .next()
+        .ok_or_else(|| cli::Error::Usage("missing command".into()))?;
+    match command.as_str() {
+        "create" => cli::create(cli::Args::parse(args, &["stdin"])?),
+        "put" => cli::put(cli::Args::parse(args, &[])?),
+        "get" => cli::get(cli::Args::parse(args, &[])?),
+        "info" => cli::info(cli::Args::parse(args, &[])?),
+        "rekey" => cli::rekey(cli::Args::parse(args, &[])?),
+        "destroy" => cli::destroy(cli::Args::parse(args, &[])?),
+        "mount" => cli::mount(cli::Args::parse(args, &[])?),
+        "nbd" => cli::nbd(cli::Args::parse(args, &["once"])?),
+        "diff" => cli::diff(cli::Args::parse(args, &[])?),
+        "help" | "--help" | "-h" => {
+            println!("{}", cli::usage());
+            Ok(())
+        }
+        _ => Err(cli::Error::Usage(format!("unknown command {}", command))),
+    }
+}
 
 fn main() {
     pretty_env_logger::init();
+    if let Err(e) = run() {
+        eprintln!("rubberhose: {}", e);
+        if let cli::Error::Usage(_) = e {
+            eprintln!("\n{}", cli::usage());
+        }
+        exit(e.exit_code());
+    }
 }
-
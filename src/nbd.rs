@@ -0,0 +1,569 @@
+use std::cmp::min;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+use crate::aspect;
+use crate::AspectCursor;
+
+/// Sent by the server to open the handshake, followed by `IHAVEOPT`
+const NBDMAGIC: u64 = 0x4e42_444d_4147_4943;
+/// Precedes every option the client sends
+const IHAVEOPT: u64 = 0x4948_4156_454f_5054;
+/// Precedes every reply to an option
+const OPTION_REPLY_MAGIC: u64 = 0x0003_e889_0455_65a9;
+const REQUEST_MAGIC: u32 = 0x2560_9513;
+const SIMPLE_REPLY_MAGIC: u32 = 0x6744_6698;
+
+const NBD_FLAG_FIXED_NEWSTYLE: u16 = 1 << 0;
+const NBD_FLAG_NO_ZEROES: u16 = 1 << 1;
+const NBD_FLAG_C_NO_ZEROES: u32 = 1 << 1;
+
+const NBD_OPT_EXPORT_NAME: u32 = 1;
+const NBD_OPT_ABORT: u32 = 2;
+const NBD_OPT_LIST: u32 = 3;
+const NBD_OPT_INFO: u32 = 6;
+const NBD_OPT_GO: u32 = 7;
+
+const NBD_REP_ACK: u32 = 1;
+const NBD_REP_SERVER: u32 = 2;
+const NBD_REP_INFO: u32 = 3;
+const NBD_REP_ERR_UNSUP: u32 = (1 << 31) + 1;
+const NBD_REP_ERR_INVALID: u32 = (1 << 31) + 3;
+
+const NBD_INFO_EXPORT: u16 = 0;
+
+const NBD_FLAG_HAS_FLAGS: u16 = 1 << 0;
+const NBD_FLAG_SEND_FLUSH: u16 = 1 << 2;
+const NBD_FLAG_SEND_TRIM: u16 = 1 << 5;
+const NBD_FLAG_SEND_WRITE_ZEROES: u16 = 1 << 6;
+
+const NBD_CMD_READ: u16 = 0;
+const NBD_CMD_WRITE: u16 = 1;
+const NBD_CMD_DISC: u16 = 2;
+const NBD_CMD_FLUSH: u16 = 3;
+const NBD_CMD_TRIM: u16 = 4;
+const NBD_CMD_WRITE_ZEROES: u16 = 6;
+
+const NBD_EIO: u32 = 5;
+const NBD_ENOMEM: u32 = 12;
+const NBD_EINVAL: u32 = 22;
+const NBD_ENOSPC: u32 = 28;
+const NBD_ENOTSUP: u32 = 95;
+
+/// Largest read or write accepted in a single request
+pub const MAX_REQUEST: u32 = 32 << 20;
+
+/// Longest option payload accepted during the handshake
+const MAX_OPTION: u32 = 4096;
+
+/// Name the single export is listed under
+const EXPORT_NAME: &[u8] = b"rubberhose";
+
+/// Serves the byte stream of an aspect as an NBD export
+///
+/// The export has the current length of the stream, so an aspect has to be
+/// sized with [`AspectCursor::set_len`] before a client can use it. Writes go
+/// straight through to the aspect's blocks; trimmed ranges are zeroed.
+pub struct Server {
+    cursor: AspectCursor,
+}
+
+fn read_u16<R: Read>(reader: &mut R) -> io::Result<u16> {
+    let mut bytes = [0; 2];
+    reader.read_exact(&mut bytes)?;
+    Ok(u16::from_be_bytes(bytes))
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_be_bytes(bytes))
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_be_bytes(bytes))
+}
+
+fn protocol_error(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Map an error from the aspect onto the error reported to the client
+fn nbd_error(e: &io::Error) -> u32 {
+    match e
+        .get_ref()
+        .and_then(|inner| inner.downcast_ref::<crate::Error>())
+    {
+        Some(e) if e.aspect() == Some(aspect::Error::ExtentFull) => NBD_ENOSPC,
+        _ => NBD_EIO,
+    }
+}
+
+impl Server {
+    pub fn new(cursor: AspectCursor) -> Server {
+        Server { cursor }
+    }
+
+    /// Release the underlying cursor
+    pub fn into_inner(self) -> AspectCursor {
+        self.cursor
+    }
+
+    /// Size of the export in bytes
+    pub fn size(&self) -> u64 {
+        self.cursor.len()
+    }
+
+    fn transmission_flags(&self) -> u16 {
+        NBD_FLAG_HAS_FLAGS | NBD_FLAG_SEND_FLUSH | NBD_FLAG_SEND_TRIM | NBD_FLAG_SEND_WRITE_ZEROES
+    }
+
+    /// Serve a single client until it disconnects, then flush whatever it
+    /// wrote, however the connection ended
+    pub fn serve<S: Read + Write>(&mut self, mut stream: S) -> io::Result<()> {
+        let served = match self.handshake(&mut stream) {
+            Ok(true) => self.transmission(&mut stream),
+            result => result.map(|_| ()),
+        };
+        let flushed = self.cursor.flush();
+        served.and(flushed)
+    }
+
+    fn option_reply<S: Write>(
+        stream: &mut S,
+        option: u32,
+        reply: u32,
+        data: &[u8],
+    ) -> io::Result<()> {
+        let mut out = Vec::with_capacity(20 + data.len());
+        out.extend_from_slice(&OPTION_REPLY_MAGIC.to_be_bytes());
+        out.extend_from_slice(&option.to_be_bytes());
+        out.extend_from_slice(&reply.to_be_bytes());
+        out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        out.extend_from_slice(data);
+        stream.write_all(&out)
+    }
+
+    /// Negotiate options, returning whether the client moved on to
+    /// transmission
+    fn handshake<S: Read + Write>(&mut self, stream: &mut S) -> io::Result<bool> {
+        let mut greeting = Vec::new();
+        greeting.extend_from_slice(&NBDMAGIC.to_be_bytes());
+        greeting.extend_from_slice(&IHAVEOPT.to_be_bytes());
+        greeting.extend_from_slice(&(NBD_FLAG_FIXED_NEWSTYLE | NBD_FLAG_NO_ZEROES).to_be_bytes());
+        stream.write_all(&greeting)?;
+        stream.flush()?;
+        let no_zeroes = read_u32(stream)? & NBD_FLAG_C_NO_ZEROES != 0;
+
+        loop {
+            if read_u64(stream)? != IHAVEOPT {
+                return Err(protocol_error("bad option magic"));
+            }
+            let option = read_u32(stream)?;
+            let len = read_u32(stream)?;
+            if len > MAX_OPTION {
+                return Err(protocol_error("option too long"));
+            }
+            let mut data = vec![0; len as usize];
+            stream.read_exact(&mut data)?;
+
+            let mut export = Vec::new();
+            export.extend_from_slice(&self.size().to_be_bytes());
+            export.extend_from_slice(&self.transmission_flags().to_be_bytes());
+            match option {
+                NBD_OPT_EXPORT_NAME => {
+                    // The old way to finish negotiation, with no reply header
+                    stream.write_all(&export)?;
+                    if !no_zeroes {
+                        stream.write_all(&[0; 124])?;
+                    }
+                    stream.flush()?;
+                    return Ok(true);
+                }
+                NBD_OPT_ABORT => {
+                    Server::option_reply(stream, option, NBD_REP_ACK, &[])?;
+                    stream.flush()?;
+                    return Ok(false);
+                }
+                NBD_OPT_LIST => {
+                    let mut server = (EXPORT_NAME.len() as u32).to_be_bytes().to_vec();
+                    server.extend_from_slice(EXPORT_NAME);
+                    Server::option_reply(stream, option, NBD_REP_SERVER, &server)?;
+                    Server::option_reply(stream, option, NBD_REP_ACK, &[])?;
+                }
+                NBD_OPT_INFO | NBD_OPT_GO => {
+                    // Any export name is accepted, there being only the one
+                    let name_len = data
+                        .get(..4)
+                        .map(|b| u32::from_be_bytes(b.try_into().unwrap()));
+                    if name_len.is_none_or(|n| data.len() < 6 + n as usize) {
+                        Server::option_reply(stream, option, NBD_REP_ERR_INVALID, &[])?;
+                        stream.flush()?;
+                        continue;
+                    }
+                    let mut info = NBD_INFO_EXPORT.to_be_bytes().to_vec();
+                    info.extend_from_slice(&export);
+                    Server::option_reply(stream, option, NBD_REP_INFO, &info)?;
+                    Server::option_reply(stream, option, NBD_REP_ACK, &[])?;
+                    if option == NBD_OPT_GO {
+                        stream.flush()?;
+                        return Ok(true);
+                    }
+                }
+                _ => Server::option_reply(stream, option, NBD_REP_ERR_UNSUP, &[])?,
+            }
+            stream.flush()?;
+        }
+    }
+
+    fn reply<S: Write>(stream: &mut S, handle: u64, error: u32, data: &[u8]) -> io::Result<()> {
+        let mut out = Vec::with_capacity(16 + data.len());
+        out.extend_from_slice(&SIMPLE_REPLY_MAGIC.to_be_bytes());
+        out.extend_from_slice(&error.to_be_bytes());
+        out.extend_from_slice(&handle.to_be_bytes());
+        out.extend_from_slice(data);
+        stream.write_all(&out)?;
+        stream.flush()
+    }
+
+    /// Overwrite a range of the export with zeros
+    fn zero(&mut self, offset: u64, len: u64) -> io::Result<()> {
+        let zeros = [0; 64 * 1024];
+        self.cursor.seek(SeekFrom::Start(offset))?;
+        let mut remaining = len;
+        while remaining > 0 {
+            let n = min(remaining, zeros.len() as u64) as usize;
+            self.cursor.write_all(&zeros[..n])?;
+            remaining -= n as u64;
+        }
+        Ok(())
+    }
+
+    fn transmission<S: Read + Write>(&mut self, stream: &mut S) -> io::Result<()> {
+        loop {
+            if read_u32(stream)? != REQUEST_MAGIC {
+                return Err(protocol_error("bad request magic"));
+            }
+            let _flags = read_u16(stream)?;
+            let command = read_u16(stream)?;
+            let handle = read_u64(stream)?;
+            let offset = read_u64(stream)?;
+            let len = read_u32(stream)?;
+            log::debug!("NBD command {} at 0x{:x}+0x{:x}", command, offset, len);
+
+            let in_range = offset
+                .checked_add(len as u64)
+                .is_some_and(|end| end <= self.size());
+            match command {
+                NBD_CMD_WRITE => {
+                    // The payload has to be consumed whatever happens to it
+                    if len > MAX_REQUEST {
+                        return Err(protocol_error("write too large"));
+                    }
+                    let mut data = vec![0; len as usize];
+                    stream.read_exact(&mut data)?;
+                    let error = if !in_range {
+                        NBD_ENOSPC
+                    } else {
+                        match self
+                            .cursor
+                            .seek(SeekFrom::Start(offset))
+                            .and_then(|_| self.cursor.write_all(&data))
+                        {
+                            Ok(()) => 0,
+                            Err(e) => nbd_error(&e),
+                        }
+                    };
+                    Server::reply(stream, handle, error, &[])?;
+                }
+                NBD_CMD_READ => {
+                    if len > MAX_REQUEST {
+                        Server::reply(stream, handle, NBD_ENOMEM, &[])?;
+                        continue;
+                    }
+                    if !in_range {
+                        Server::reply(stream, handle, NBD_EINVAL, &[])?;
+                        continue;
+                    }
+                    let mut data = vec![0; len as usize];
+                    match self
+                        .cursor
+                        .seek(SeekFrom::Start(offset))
+                        .and_then(|_| self.cursor.read_exact(&mut data))
+                    {
+                        Ok(()) => Server::reply(stream, handle, 0, &data)?,
+                        Err(e) => Server::reply(stream, handle, nbd_error(&e), &[])?,
+                    }
+                }
+                NBD_CMD_TRIM | NBD_CMD_WRITE_ZEROES => {
+                    let error = if !in_range {
+                        NBD_ENOSPC
+                    } else {
+                        match self.zero(offset, len as u64) {
+                            Ok(()) => 0,
+                            Err(e) => nbd_error(&e),
+                        }
+                    };
+                    Server::reply(stream, handle, error, &[])?;
+                }
+                NBD_CMD_FLUSH => {
+                    let error = match self.cursor.flush() {
+                        Ok(()) => 0,
+                        Err(e) => nbd_error(&e),
+                    };
+                    Server::reply(stream, handle, error, &[])?;
+                }
+                NBD_CMD_DISC => return Ok(()),
+                _ => Server::reply(stream, handle, NBD_ENOTSUP, &[])?,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::{RawBlock, BLOCK_SIZE};
+    use crate::test_util::{extent_on, new_cursor, random_disk};
+    use crate::{BlockDevice, RAMDisk};
+    use std::collections::HashMap;
+    use std::os::unix::net::UnixStream;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    /// A RAM disk behind a write-back cache: writes only reach the shared
+    /// disk, which outlives the server, once flushed
+    #[derive(Debug)]
+    struct CachedDisk {
+        disk: Arc<Mutex<RAMDisk>>,
+        cache: HashMap<u64, RawBlock>,
+    }
+
+    impl BlockDevice for CachedDisk {
+        fn read(&mut self, index: u64) -> crate::Result<RawBlock> {
+            match self.cache.get(&index) {
+                Some(block) => Ok(block.clone()),
+                None => self.disk.lock().unwrap().read(index),
+            }
+        }
+
+        fn write(&mut self, index: u64, block: &RawBlock) -> crate::Result<()> {
+            self.cache.insert(index, block.clone());
+            Ok(())
+        }
+
+        fn len(&self) -> crate::Result<u64> {
+            self.disk.lock().unwrap().len()
+        }
+
+        fn block_size(&self) -> usize {
+            self.disk.lock().unwrap().block_size()
+        }
+
+        fn flush(&mut self) -> crate::Result<()> {
+            let mut disk = self.disk.lock().unwrap();
+            for (index, block) in self.cache.drain() {
+                disk.write(index, &block)?;
+            }
+            Ok(())
+        }
+    }
+
+    /// Just enough of an NBD client to drive the server
+    struct Client {
+        stream: UnixStream,
+        handle: u64,
+    }
+
+    impl Client {
+        /// Connect to a server, negotiating the export with NBD_OPT_GO
+        fn connect(stream: UnixStream) -> (Client, u64) {
+            let mut client = Client { stream, handle: 0 };
+            assert_eq!(read_u64(&mut client.stream).unwrap(), NBDMAGIC);
+            assert_eq!(read_u64(&mut client.stream).unwrap(), IHAVEOPT);
+            assert!(read_u16(&mut client.stream).unwrap() & NBD_FLAG_FIXED_NEWSTYLE != 0);
+            client
+                .stream
+                .write_all(&NBD_FLAG_C_NO_ZEROES.to_be_bytes())
+                .unwrap();
+
+            client.option(NBD_OPT_LIST, &[]);
+            assert_eq!(client.option_reply(NBD_OPT_LIST).0, NBD_REP_SERVER);
+            assert_eq!(client.option_reply(NBD_OPT_LIST).0, NBD_REP_ACK);
+            client.option(99, &[]);
+            assert_eq!(client.option_reply(99).0, NBD_REP_ERR_UNSUP);
+
+            client.option(NBD_OPT_GO, &[0, 0, 0, 0, 0, 0]);
+            let (reply, info) = client.option_reply(NBD_OPT_GO);
+            assert_eq!(reply, NBD_REP_INFO);
+            assert_eq!(
+                u16::from_be_bytes(info[..2].try_into().unwrap()),
+                NBD_INFO_EXPORT
+            );
+            let size = u64::from_be_bytes(info[2..10].try_into().unwrap());
+            assert_eq!(client.option_reply(NBD_OPT_GO).0, NBD_REP_ACK);
+            (client, size)
+        }
+
+        fn option(&mut self, option: u32, data: &[u8]) {
+            let mut out = IHAVEOPT.to_be_bytes().to_vec();
+            out.extend_from_slice(&option.to_be_bytes());
+            out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+            out.extend_from_slice(data);
+            self.stream.write_all(&out).unwrap();
+        }
+
+        fn option_reply(&mut self, option: u32) -> (u32, Vec<u8>) {
+            assert_eq!(read_u64(&mut self.stream).unwrap(), OPTION_REPLY_MAGIC);
+            assert_eq!(read_u32(&mut self.stream).unwrap(), option);
+            let reply = read_u32(&mut self.stream).unwrap();
+            let mut data = vec![0; read_u32(&mut self.stream).unwrap() as usize];
+            self.stream.read_exact(&mut data).unwrap();
+            (reply, data)
+        }
+
+        /// Issue a command, returning the error and any data read
+        fn command(
+            &mut self,
+            command: u16,
+            offset: u64,
+            len: u32,
+            payload: &[u8],
+        ) -> (u32, Vec<u8>) {
+            self.handle += 1;
+            let mut out = REQUEST_MAGIC.to_be_bytes().to_vec();
+            out.extend_from_slice(&0u16.to_be_bytes());
+            out.extend_from_slice(&command.to_be_bytes());
+            out.extend_from_slice(&self.handle.to_be_bytes());
+            out.extend_from_slice(&offset.to_be_bytes());
+            out.extend_from_slice(&len.to_be_bytes());
+            out.extend_from_slice(payload);
+            self.stream.write_all(&out).unwrap();
+            if command == NBD_CMD_DISC {
+                return (0, Vec::new());
+            }
+
+            assert_eq!(read_u32(&mut self.stream).unwrap(), SIMPLE_REPLY_MAGIC);
+            let error = read_u32(&mut self.stream).unwrap();
+            assert_eq!(read_u64(&mut self.stream).unwrap(), self.handle);
+            let mut data = Vec::new();
+            if command == NBD_CMD_READ && error == 0 {
+                data.resize(len as usize, 0);
+                self.stream.read_exact(&mut data).unwrap();
+            }
+            (error, data)
+        }
+    }
+
+    fn start_server(size: u64) -> (Client, u64, thread::JoinHandle<AspectCursor>) {
+        let mut cursor = new_cursor(64, BLOCK_SIZE);
+        cursor.set_len(size).unwrap();
+
+        let (client, server) = UnixStream::pair().unwrap();
+        let handle = thread::spawn(move || {
+            let mut nbd = Server::new(cursor);
+            nbd.serve(server).unwrap();
+            nbd.into_inner()
+        });
+        let (client, size) = Client::connect(client);
+        (client, size, handle)
+    }
+
+    #[test]
+    fn read_write_trim() {
+        let (mut client, size, server) = start_server(20000);
+        assert_eq!(size, 20000);
+
+        let data: Vec<u8> = (0..5000u32).map(|i| (i % 253) as u8).collect();
+        assert_eq!(client.command(NBD_CMD_WRITE, 1000, 5000, &data).0, 0);
+        assert_eq!(client.command(NBD_CMD_FLUSH, 0, 0, &[]).0, 0);
+        assert_eq!(
+            client.command(NBD_CMD_READ, 1000, 5000, &[]),
+            (0, data.clone())
+        );
+        assert_eq!(
+            client.command(NBD_CMD_READ, 0, 1000, &[]),
+            (0, vec![0; 1000])
+        );
+
+        assert_eq!(client.command(NBD_CMD_TRIM, 2000, 1000, &[]).0, 0);
+        let (error, read) = client.command(NBD_CMD_READ, 1000, 5000, &[]);
+        assert_eq!(error, 0);
+        assert_eq!(&read[..1000], &data[..1000]);
+        assert!(read[1000..2000].iter().all(|&b| b == 0));
+        assert_eq!(&read[2000..], &data[2000..]);
+        client.command(NBD_CMD_DISC, 0, 0, &[]);
+
+        let mut cursor = server.join().unwrap();
+        assert_eq!(cursor.len(), 20000);
+        let mut read = vec![0; 1000];
+        cursor.seek(SeekFrom::Start(1000)).unwrap();
+        cursor.read_exact(&mut read).unwrap();
+        assert_eq!(read, data[..1000]);
+    }
+
+    #[test]
+    fn out_of_range() {
+        let (mut client, size, server) = start_server(4096);
+        assert_eq!(
+            client.command(NBD_CMD_READ, size - 10, 20, &[]).0,
+            NBD_EINVAL
+        );
+        assert_eq!(
+            client.command(NBD_CMD_WRITE, size, 4, b"nope").0,
+            NBD_ENOSPC
+        );
+        assert_eq!(client.command(NBD_CMD_READ, u64::MAX, 1, &[]).0, NBD_EINVAL);
+        assert_eq!(client.command(42, 0, 0, &[]).0, NBD_ENOTSUP);
+        // The connection is still usable after errors
+        assert_eq!(client.command(NBD_CMD_READ, 0, 4, &[]), (0, vec![0; 4]));
+        client.command(NBD_CMD_DISC, 0, 0, &[]);
+        assert_eq!(server.join().unwrap().len(), 4096);
+    }
+
+    #[test]
+    fn disconnect_flushes() {
+        let disk = Arc::new(Mutex::new(random_disk(64, BLOCK_SIZE)));
+        let open = || {
+            let disk = CachedDisk {
+                disk: Arc::clone(&disk),
+                cache: HashMap::new(),
+            };
+            extent_on(disk, None).into_handle()
+        };
+        let extent = open();
+        let keyword = extent.keyword("hello".to_string()).unwrap();
+        let mut cursor = AspectCursor::new(extent.create_aspect(keyword).unwrap()).unwrap();
+        cursor.set_len(4096).unwrap();
+        cursor.flush().unwrap();
+
+        // A client that disconnects without flushing, and one that just hangs up
+        for (data, disconnect) in [(b"first", true), (b"again", false)] {
+            let (client, server) = UnixStream::pair().unwrap();
+            let handle = thread::spawn(move || {
+                let mut nbd = Server::new(cursor);
+                let result = nbd.serve(server);
+                (nbd.into_inner(), result)
+            });
+            let (mut client, _) = Client::connect(client);
+            assert_eq!(client.command(NBD_CMD_WRITE, 100, 5, data).0, 0);
+            if disconnect {
+                client.command(NBD_CMD_DISC, 0, 0, &[]);
+            }
+            drop(client);
+            let result;
+            (cursor, result) = handle.join().unwrap();
+            assert_eq!(result.is_ok(), disconnect);
+
+            // The served cursor still holds its cache, a fresh one sees only the disk
+            let extent = open();
+            let keyword = extent.keyword("hello".to_string()).unwrap();
+            let mut reopened = AspectCursor::new(extent.open_aspect(keyword).unwrap()).unwrap();
+            let mut read = [0; 5];
+            reopened.seek(SeekFrom::Start(100)).unwrap();
+            reopened.read_exact(&mut read).unwrap();
+            assert_eq!(&read, data);
+        }
+    }
+}
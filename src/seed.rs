@@ -0,0 +1,199 @@
+use openssl::error::ErrorStack;
+use openssl::rand::rand_bytes;
+use std::mem::size_of;
+
+use crate::block::RawBlock;
+use crate::keyword::{hkdf, HKDF_SALT};
+use crate::Keyword;
+
+/// Size of a single pointer slot within a seed sector
+pub const SLOT_SIZE: usize = 2 * size_of::<u64>();
+
+/// Number of slots a seed sector of `block_size` bytes is divided into
+pub fn n_slots(block_size: usize) -> usize {
+    block_size / SLOT_SIZE
+}
+
+/// Number of slots within a seed sector an aspect may use
+pub const N_CANDIDATES: usize = 4;
+
+/// Marks a slot as holding a pointer, once the pad is removed
+const SLOT_FLAG: u64 = u64::MAX;
+
+/// Locates and masks an aspect's pointer slot within a seed sector
+///
+/// The slot is one of a handful of candidate positions derived from the
+/// keyword. Its contents are a flag and the index of the aspect's first block,
+/// XOR'd with a key-derived pad, so they look no different from the random
+/// fill or the slots of other aspects around them.
+pub struct SeedKey {
+    candidates: [usize; N_CANDIDATES],
+    pads: [[u8; SLOT_SIZE]; N_CANDIDATES],
+}
+
+impl SeedKey {
+    /// The seed key of a keyword, for seed sectors of `block_size` bytes
+    pub fn new(keyword: &Keyword, block_size: usize) -> Result<SeedKey, ErrorStack> {
+        let n_slots = n_slots(block_size);
+        let mut positions = [0; 128];
+        hkdf(
+            HKDF_SALT,
+            keyword.hash(),
+            b"rubberhose seed slots",
+            &mut positions,
+        )?;
+        let mut candidates = [0; N_CANDIDATES];
+        let mut n = 0;
+        for position in positions
+            .chunks(2)
+            .map(|p| u16::from_be_bytes([p[0], p[1]]) as usize % n_slots)
+        {
+            if n < N_CANDIDATES && !candidates[..n].contains(&position) {
+                candidates[n] = position;
+                n += 1;
+            }
+        }
+        // 64 draws from at least 32 slots fail to produce 4 distinct ones with
+        // negligible probability, but fall back to a fixed spread regardless
+        while n < N_CANDIDATES {
+            candidates[n] = (candidates[n - 1] + n_slots / N_CANDIDATES) % n_slots;
+            n += 1;
+        }
+
+        let mut pad_bytes = [0; SLOT_SIZE * N_CANDIDATES];
+        hkdf(
+            HKDF_SALT,
+            keyword.hash(),
+            b"rubberhose seed pads",
+            &mut pad_bytes,
+        )?;
+        let mut pads = [[0; SLOT_SIZE]; N_CANDIDATES];
+        for (pad, bytes) in pads.iter_mut().zip(pad_bytes.chunks(SLOT_SIZE)) {
+            pad.copy_from_slice(bytes);
+        }
+        Ok(SeedKey { candidates, pads })
+    }
+
+    /// Slots the aspect may occupy, in order of preference
+    pub fn candidates(&self) -> &[usize; N_CANDIDATES] {
+        &self.candidates
+    }
+
+    fn slot_range(slot: usize) -> std::ops::Range<usize> {
+        slot * SLOT_SIZE..(slot + 1) * SLOT_SIZE
+    }
+
+    /// Decode every candidate slot of the sector that holds a flagged
+    /// pointer, returning the slots and pointers in order of preference
+    pub fn read(&self, sector: &RawBlock) -> Vec<(usize, u64)> {
+        self.candidates
+            .iter()
+            .zip(self.pads.iter())
+            .filter_map(|(&slot, pad)| {
+                let mut plain = [0; SLOT_SIZE];
+                for (p, (c, k)) in plain
+                    .iter_mut()
+                    .zip(sector.as_ref()[SeedKey::slot_range(slot)].iter().zip(pad))
+                {
+                    *p = c ^ k;
+                }
+                let flag = u64::from_be_bytes(plain[..8].try_into().unwrap());
+                let pointer = u64::from_be_bytes(plain[8..].try_into().unwrap());
+                (flag == SLOT_FLAG).then_some((slot, pointer))
+            })
+            .collect()
+    }
+
+    /// Store a pointer in one of the candidate slots, leaving the rest of the
+    /// sector untouched
+    pub fn write(&self, sector: &mut RawBlock, slot: usize, pointer: u64) {
+        let n = self.candidates.iter().position(|&c| c == slot).unwrap();
+        let mut plain = [0; SLOT_SIZE];
+        plain[..8].copy_from_slice(&SLOT_FLAG.to_be_bytes());
+        plain[8..].copy_from_slice(&pointer.to_be_bytes());
+        for (c, (p, k)) in sector.as_mut()[SeedKey::slot_range(slot)]
+            .iter_mut()
+            .zip(plain.iter().zip(&self.pads[n]))
+        {
+            *c = p ^ k;
+        }
+    }
+
+    /// Overwrite a slot with random data, as if it had never held a pointer
+    pub fn erase(sector: &mut RawBlock, slot: usize) -> Result<(), ErrorStack> {
+        rand_bytes(&mut sector.as_mut()[SeedKey::slot_range(slot)])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::{BLOCK_SIZE, MAX_BLOCK_SIZE, MIN_BLOCK_SIZE};
+    use crate::keyword::TEST_KDF_PARAMS;
+
+    fn keyword(text: &str) -> Keyword {
+        Keyword::new(text.to_string(), &[0; 32], TEST_KDF_PARAMS).unwrap()
+    }
+
+    fn seed_key(text: &str) -> SeedKey {
+        SeedKey::new(&keyword(text), BLOCK_SIZE).unwrap()
+    }
+
+    #[test]
+    fn distinct_candidates() {
+        let keyword = keyword("hello");
+        for block_size in [MIN_BLOCK_SIZE, BLOCK_SIZE, MAX_BLOCK_SIZE] {
+            let key = SeedKey::new(&keyword, block_size).unwrap();
+            let candidates = key.candidates();
+            for (i, a) in candidates.iter().enumerate() {
+                assert!(*a < n_slots(block_size));
+                assert!(!candidates[i + 1..].contains(a));
+            }
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let key = seed_key("hello");
+        let mut sector = RawBlock::new_rand(BLOCK_SIZE).unwrap();
+        assert!(key.read(&sector).is_empty());
+        let slot = key.candidates()[2];
+        key.write(&mut sector, slot, 1234);
+        assert_eq!(key.read(&sector), vec![(slot, 1234)]);
+        assert!(seed_key("other")
+            .read(&sector)
+            .iter()
+            .all(|&(_, p)| p != 1234));
+    }
+
+    #[test]
+    fn erase_slot() {
+        let key = seed_key("hello");
+        let mut sector = RawBlock::new_rand(BLOCK_SIZE).unwrap();
+        let (first, second) = (key.candidates()[0], key.candidates()[1]);
+        key.write(&mut sector, first, 1);
+        key.write(&mut sector, second, 2);
+        let before = sector.clone();
+        SeedKey::erase(&mut sector, first).unwrap();
+        assert_eq!(key.read(&sector), vec![(second, 2)]);
+        assert_eq!(
+            &sector.as_ref()[SeedKey::slot_range(second)],
+            &before.as_ref()[SeedKey::slot_range(second)]
+        );
+    }
+
+    #[test]
+    fn only_touches_own_slot() {
+        let key = seed_key("hello");
+        let original = RawBlock::new_rand(BLOCK_SIZE).unwrap();
+        let mut sector = original.clone();
+        let slot = key.candidates()[0];
+        key.write(&mut sector, slot, 7);
+        let changed: Vec<usize> = (0..BLOCK_SIZE)
+            .filter(|&i| original.as_ref()[i] != sector.as_ref()[i])
+            .collect();
+        assert!(changed
+            .iter()
+            .all(|i| SeedKey::slot_range(slot).contains(i)));
+    }
+}
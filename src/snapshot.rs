@@ -0,0 +1,240 @@
+use std::fmt;
+use std::io;
+
+use crate::device::BlockDevice;
+use crate::error::Result;
+
+/// What an observer comparing two snapshots of a disk could learn
+///
+/// The snapshots are any two block devices, such as a `RAMDisk` cloned before
+/// a series of aspect operations and the disk after them, or two copies of an
+/// image file. Writes are deniable only while the changed blocks look like
+/// those a stream of random overwrites would leave: spread evenly over the
+/// disk, not clustered around the seed index of a keyword, and with contents
+/// of full entropy.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diff {
+    pub n_blocks: u64,
+    /// Indices of the blocks whose contents differ
+    pub changed: Vec<u64>,
+    /// Longest run of changed blocks at consecutive indices
+    pub longest_run: u64,
+    /// Kolmogorov-Smirnov distance between the changed blocks' offsets
+    /// forward from a seed index and a uniform spread: near 0 if they are
+    /// spread evenly, near 1 if they huddle after the seed index
+    pub seed_clustering: Option<f64>,
+    /// Entropy in bits per byte of the least random changed block
+    pub min_entropy: Option<f64>,
+    /// Mean entropy in bits per byte of the changed blocks
+    pub mean_entropy: Option<f64>,
+}
+
+/// Shannon entropy of `bytes` in bits per byte, at most 8
+pub fn entropy(bytes: &[u8]) -> f64 {
+    let mut counts = [0u64; 256];
+    for &byte in bytes {
+        counts[byte as usize] += 1;
+    }
+    let n = bytes.len() as f64;
+    counts
+        .iter()
+        .filter(|&&count| count > 0)
+        .map(|&count| {
+            let p = count as f64 / n;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+/// Kolmogorov-Smirnov distance between `samples`, each in [0, 1), and the
+/// uniform distribution
+fn ks_uniform(mut samples: Vec<f64>) -> f64 {
+    samples.sort_by(f64::total_cmp);
+    let m = samples.len() as f64;
+    samples
+        .iter()
+        .enumerate()
+        .map(|(i, &x)| f64::max((i + 1) as f64 / m - x, x - i as f64 / m))
+        .fold(0.0, f64::max)
+}
+
+/// Compare two snapshots of a disk block by block. `seed_index` is that of a
+/// keyword whose aspect was written in between, if known
+pub fn diff<A, B>(before: &mut A, after: &mut B, seed_index: Option<u64>) -> Result<Diff>
+where
+    A: BlockDevice + ?Sized,
+    B: BlockDevice + ?Sized,
+{
+    let n_blocks = before.len()?;
+    if after.len()? != n_blocks || after.block_size() != before.block_size() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "snapshots of different geometry",
+        )
+        .into());
+    }
+
+    let mut changed = Vec::new();
+    let mut entropies = Vec::new();
+    let (mut run, mut longest_run) = (0, 0);
+    for index in 0..n_blocks {
+        let block = after.read(index)?;
+        if before.read(index)? == block {
+            run = 0;
+            continue;
+        }
+        changed.push(index);
+        entropies.push(entropy(block.as_ref()));
+        run += 1;
+        longest_run = longest_run.max(run);
+    }
+
+    let seed_clustering = match seed_index {
+        Some(seed) if !changed.is_empty() => Some(ks_uniform(
+            changed
+                .iter()
+                .map(|&index| ((index + n_blocks - seed) % n_blocks) as f64 / n_blocks as f64)
+                .collect(),
+        )),
+        _ => None,
+    };
+    let (min_entropy, mean_entropy) = match entropies.len() {
+        0 => (None, None),
+        n => (
+            entropies.iter().copied().reduce(f64::min),
+            Some(entropies.iter().sum::<f64>() / n as f64),
+        ),
+    };
+    Ok(Diff {
+        n_blocks,
+        changed,
+        longest_run,
+        seed_clustering,
+        min_entropy,
+        mean_entropy,
+    })
+}
+
+impl fmt::Display for Diff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let optional =
+            |value: Option<f64>| value.map_or("-".to_string(), |value| format!("{:.3}", value));
+        writeln!(f, "image blocks:    {}", self.n_blocks)?;
+        writeln!(f, "changed blocks:  {}", self.changed.len())?;
+        writeln!(f, "longest run:     {}", self.longest_run)?;
+        writeln!(f, "seed clustering: {}", optional(self.seed_clustering))?;
+        writeln!(f, "min entropy:     {}", optional(self.min_entropy))?;
+        write!(f, "mean entropy:    {}", optional(self.mean_entropy))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::allocator::Linear;
+    use crate::block::BLOCK_SIZE;
+    use crate::extent::Chaff;
+    use crate::test_util::{extent_on, random_disk};
+    use crate::{Block, Extent, ExtentHandle, RAMDisk, RawBlock};
+
+    const N_BLOCKS: usize = 1024;
+
+    /// A randomised disk, and a handle onto a copy of it
+    fn extent_with<F>(configure: F) -> (RAMDisk, ExtentHandle)
+    where
+        F: FnOnce(Extent<RAMDisk>) -> Extent<RAMDisk>,
+    {
+        let disk = random_disk(N_BLOCKS, BLOCK_SIZE);
+        let extent = configure(extent_on(disk.clone(), None));
+        (disk, extent.into_handle())
+    }
+
+    /// Create an aspect of `n_blocks` and write all of it, returning the
+    /// difference it made to the disk along with its seed index
+    fn write_aspect(before: &mut RAMDisk, extent: &ExtentHandle, n_blocks: u64) -> Diff {
+        let keyword = extent.keyword("hello".to_string()).unwrap();
+        let seed_index = keyword.seed_index(extent.n_blocks()).unwrap();
+        let mut aspect = extent.create_aspect(keyword).unwrap();
+        for _ in 1..n_blocks {
+            aspect.push_block().unwrap();
+        }
+        for block_id in 0..n_blocks {
+            let mut block = Block::new(aspect.block_size());
+            block.data_mut().fill(block_id as u8);
+            aspect.write_block(block_id, block).unwrap();
+        }
+        let mut extent = extent.lock().unwrap();
+        extent.flush().unwrap();
+        // The extent's device is boxed; read it back through the extent
+        let mut after = RAMDisk::new(N_BLOCKS);
+        for index in 0..N_BLOCKS as u64 {
+            after
+                .write(index, &extent.read_raw(index).unwrap())
+                .unwrap();
+        }
+        diff(before, &mut after, Some(seed_index)).unwrap()
+    }
+
+    #[test]
+    fn entropy_bounds() {
+        assert_eq!(entropy(&[7; 100]), 0.0);
+        assert_eq!(entropy(&(0..=255).collect::<Vec<u8>>()), 8.0);
+        assert!(entropy(RawBlock::new_rand(BLOCK_SIZE).unwrap().as_ref()) > 7.5);
+        assert!(entropy(b"the quick brown fox jumps over the lazy dog") < 5.0);
+    }
+
+    #[test]
+    fn ks_distance() {
+        let even: Vec<f64> = (0..100).map(|i| i as f64 / 100.0).collect();
+        assert!(ks_uniform(even) <= 0.01 + f64::EPSILON);
+        assert!(ks_uniform(vec![0.0, 0.01, 0.02, 0.03]) > 0.95);
+    }
+
+    #[test]
+    fn identical_snapshots() {
+        let mut disk = RAMDisk::new(8);
+        let report = diff(&mut disk.clone(), &mut disk, Some(3)).unwrap();
+        assert!(report.changed.is_empty());
+        assert_eq!(report.seed_clustering, None);
+        assert_eq!(report.min_entropy, None);
+        assert!(diff(&mut RAMDisk::new(8), &mut RAMDisk::new(9), None).is_err());
+    }
+
+    #[test]
+    fn linear_layout_is_exposed() {
+        let (mut before, extent) = extent_with(|extent| extent.with_allocator(Linear));
+        let report = write_aspect(&mut before, &extent, 32);
+        // The seed sector and 32 blocks, nearly all in a row after the seed
+        assert_eq!(report.changed.len(), 33);
+        assert!(report.longest_run >= 16, "{}", report);
+        assert!(report.seed_clustering.unwrap() > 0.9, "{}", report);
+    }
+
+    /// Regression gate for the random allocator: an aspect's blocks must not
+    /// give away where its seed is, nor form runs
+    #[test]
+    fn random_layout_is_spread() {
+        let (mut before, extent) = extent_with(|extent| extent);
+        let report = write_aspect(&mut before, &extent, 32);
+        assert_eq!(report.changed.len(), 33);
+        assert!(report.longest_run <= 4, "{}", report);
+        // Well above the 0.1% critical value of about 0.34 for 33 samples
+        assert!(report.seed_clustering.unwrap() < 0.45, "{}", report);
+        assert!(report.min_entropy.unwrap() > 7.5, "{}", report);
+    }
+
+    /// Regression gate for chaff: the real writes must be a minority of the
+    /// changed blocks, and the decoys as random as the rest
+    #[test]
+    fn chaff_drowns_writes() {
+        let chaff = Chaff {
+            free_blocks: 64,
+            owned_blocks: 0,
+        };
+        let (mut before, extent) = extent_with(|extent| extent.with_chaff(chaff));
+        let report = write_aspect(&mut before, &extent, 8);
+        assert!(report.changed.len() >= 9 + 60, "{}", report);
+        assert!(report.seed_clustering.unwrap() < 0.3, "{}", report);
+        assert!(report.min_entropy.unwrap() > 7.5, "{}", report);
+    }
+}
@@ -0,0 +1,43 @@
+//! Fixtures shared by the unit tests
+
+use crate::keyword::TEST_KDF_PARAMS;
+use crate::{AspectCursor, BlockDevice, Extent, ExtentHandle, RAMDisk};
+
+/// A RAM disk of `n_blocks` blocks of `block_size` bytes, randomised as
+/// before first use
+pub(crate) fn random_disk(n_blocks: usize, block_size: usize) -> RAMDisk {
+    let mut disk = RAMDisk::with_block_size(n_blocks, block_size);
+    disk.randomize().unwrap();
+    disk
+}
+
+/// An extent on `device` with cheap key derivation, guarded until `guard`
+/// aspects are unlocked if given
+pub(crate) fn extent_on<T: BlockDevice>(device: T, guard: Option<usize>) -> Extent<T> {
+    let extent = Extent::new(device)
+        .unwrap()
+        .with_kdf_params(TEST_KDF_PARAMS);
+    match guard {
+        Some(n_aspects) => extent.with_guard(n_aspects),
+        None => extent,
+    }
+}
+
+/// An extent on a fresh [`random_disk`]
+pub(crate) fn random_extent(
+    n_blocks: usize,
+    block_size: usize,
+    guard: Option<usize>,
+) -> ExtentHandle {
+    extent_on(random_disk(n_blocks, block_size), guard).into_handle()
+}
+
+/// A cursor over a new, empty aspect with the keyword "hello" on a fresh
+/// [`random_extent`]
+pub(crate) fn new_cursor(n_blocks: usize, block_size: usize) -> AspectCursor {
+    let extent = random_extent(n_blocks, block_size, None);
+    let aspect = extent
+        .create_aspect(extent.keyword("hello".to_string()).unwrap())
+        .unwrap();
+    AspectCursor::new(aspect).unwrap()
+}